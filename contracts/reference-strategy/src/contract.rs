@@ -0,0 +1,160 @@
+#[cfg(not(feature = "library"))]
+use cosmwasm_std::entry_point;
+use cosmwasm_std::{
+    attr, to_binary, Addr, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Uint128,
+};
+use cw2::set_contract_version;
+
+use crate::msg::{
+    BidEntry, ConfigResponse, ExecuteMsg, InstantiateMsg, NextBidsResponse, QueryMsg, VaultState,
+};
+use crate::state::{State, STATE};
+use crate::ContractError;
+use crate::ContractError::Unauthorized;
+
+// version info for migration info
+const CONTRACT_NAME: &str = "crates.io:reference-strategy";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn instantiate(
+    deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    msg: InstantiateMsg,
+) -> Result<Response, ContractError> {
+    let state = State {
+        owner: deps
+            .api
+            .addr_validate(&msg.owner.to_string().to_lowercase())?,
+        premium_slots: msg.premium_slots,
+    };
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![attr("action", "instantiate")]))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn execute(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
+    match msg {
+        // Only owner can execute
+        ExecuteMsg::UpdateConfig {
+            owner,
+            premium_slots,
+        } => update_config(deps, info, owner, premium_slots),
+    }
+}
+
+fn update_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    owner: Option<Addr>,
+    premium_slots: Option<Vec<u8>>,
+) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage)?;
+    if state.owner.to_string().to_lowercase() != info.sender.to_string().to_lowercase() {
+        return Err(Unauthorized {});
+    }
+    let mut attributes = vec![attr("action", "update_config"), attr("from", info.sender)];
+    if let Some(owner) = owner {
+        state.owner = deps.api.addr_validate(&owner.to_string().to_lowercase())?;
+        attributes.push(attr("owner", state.owner.to_string()));
+    }
+    if let Some(premium_slots) = premium_slots {
+        state.premium_slots = premium_slots;
+        attributes.push(attr("premium_slots", state.premium_slots.len().to_string()));
+    }
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(attributes))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        // Target UST per premium slot for Rebalance of the vault
+        QueryMsg::NextBids { vault_state } => to_binary(&query_next_bids(deps, vault_state)?),
+        QueryMsg::Config {} => to_binary(&query_config(deps)?),
+    }
+}
+
+// Spread UST in bids and biddable UST of the vault evenly over premium slots
+// Remainder goes to the first premium slot
+fn query_next_bids(deps: Deps, vault_state: VaultState) -> StdResult<NextBidsResponse> {
+    let state = STATE.load(deps.storage)?;
+    if state.premium_slots.is_empty() {
+        return Ok(NextBidsResponse { bids: vec![] });
+    }
+    let mut total = vault_state.biddable_ust;
+    for bid in vault_state.bids.iter() {
+        total += bid.amount;
+    }
+    let count = state.premium_slots.len() as u128;
+    let amount = Uint128::from(total.u128() / count);
+    let remainder = Uint128::from(total.u128() % count);
+    Ok(NextBidsResponse {
+        bids: state
+            .premium_slots
+            .iter()
+            .enumerate()
+            .map(|(i, premium_slot)| BidEntry {
+                premium_slot: *premium_slot,
+                amount: if i == 0 { amount + remainder } else { amount },
+            })
+            .collect(),
+    })
+}
+
+fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(ConfigResponse {
+        owner: state.owner.to_string(),
+        premium_slots: state.premium_slots,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::from_binary;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+
+    #[test]
+    fn spreads_evenly_over_premium_slots() {
+        let mut deps = mock_dependencies(&[]);
+        let msg = InstantiateMsg {
+            owner: Addr::unchecked("owner"),
+            premium_slots: vec![1, 3, 5],
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+        let msg = QueryMsg::NextBids {
+            vault_state: VaultState {
+                biddable_ust: Uint128::from(400_000u128),
+                bids: vec![BidEntry {
+                    premium_slot: 10,
+                    amount: Uint128::from(600_001u128),
+                }],
+            },
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let value: NextBidsResponse = from_binary(&res).unwrap();
+        let amounts: Vec<(u8, u128)> = value
+            .bids
+            .iter()
+            .map(|bid| (bid.premium_slot, bid.amount.u128()))
+            .collect();
+        assert_eq!(vec![(1, 333_335), (3, 333_333), (5, 333_333)], amounts);
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            premium_slots: Some(vec![]),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+    }
+}
@@ -0,0 +1,14 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::Addr;
+use cw_storage_plus::Item;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct State {
+    pub owner: Addr,
+    // Premium slots to spread UST of vault over
+    pub premium_slots: Vec<u8>,
+}
+
+pub const STATE: Item<State> = Item::new("state");
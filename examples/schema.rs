@@ -4,9 +4,13 @@ use std::fs::create_dir_all;
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
 use terra_deposit_withdraw::msg::{
-    ActivatableResponse, BalanceResponse, ClaimableResponse, ConfigResponse, ExecuteMsg,
-    InfoResponse, InstantiateMsg, PermissionResponse, QueryMsg, TimestampResponse,
-    TotalCapResponse, UnlockableResponse,
+    ActivatableResponse, AllAccountsResponse, AllowanceResponse, AllowlistResponse, AssetsResponse,
+    BalanceResponse, ClaimableResponse, ConfigResponse, Cw20HookMsg, EpochRequestResponse,
+    EpochResponse, ExecuteMsg, FeesResponse, InfoResponse, InstantiateMsg, NavBreakdownResponse,
+    NextBidsResponse, OracleStatusResponse, PermissionResponse, PreviewWithdrawBLunaResponse,
+    PreviewWithdrawUstResponse, QueryMsg, RemainingCapacityResponse, SharesResponse, StrategyQuery,
+    SuggestSlotsResponse, SurplusResponse, TimestampResponse, TokenInfoResponse, TotalCapResponse,
+    UnlockableResponse, WithdrawQueueResponse, WithdrawReceiptResponse, WithdrawTicketsResponse,
 };
 use terra_deposit_withdraw::state::State;
 
@@ -18,15 +22,37 @@ fn main() {
 
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
+    export_schema(&schema_for!(Cw20HookMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
+    export_schema(&schema_for!(StrategyQuery), &out_dir);
+    export_schema(&schema_for!(NextBidsResponse), &out_dir);
     export_schema(&schema_for!(State), &out_dir);
     export_schema(&schema_for!(InfoResponse), &out_dir);
     export_schema(&schema_for!(ConfigResponse), &out_dir);
     export_schema(&schema_for!(BalanceResponse), &out_dir);
     export_schema(&schema_for!(TotalCapResponse), &out_dir);
+    export_schema(&schema_for!(NavBreakdownResponse), &out_dir);
+    export_schema(&schema_for!(OracleStatusResponse), &out_dir);
+    export_schema(&schema_for!(FeesResponse), &out_dir);
+    export_schema(&schema_for!(SurplusResponse), &out_dir);
+    export_schema(&schema_for!(SuggestSlotsResponse), &out_dir);
+    export_schema(&schema_for!(RemainingCapacityResponse), &out_dir);
+    export_schema(&schema_for!(AllowlistResponse), &out_dir);
+    export_schema(&schema_for!(WithdrawReceiptResponse), &out_dir);
+    export_schema(&schema_for!(WithdrawQueueResponse), &out_dir);
+    export_schema(&schema_for!(WithdrawTicketsResponse), &out_dir);
+    export_schema(&schema_for!(EpochResponse), &out_dir);
+    export_schema(&schema_for!(EpochRequestResponse), &out_dir);
     export_schema(&schema_for!(ActivatableResponse), &out_dir);
     export_schema(&schema_for!(ClaimableResponse), &out_dir);
     export_schema(&schema_for!(PermissionResponse), &out_dir);
     export_schema(&schema_for!(UnlockableResponse), &out_dir);
     export_schema(&schema_for!(TimestampResponse), &out_dir);
+    export_schema(&schema_for!(TokenInfoResponse), &out_dir);
+    export_schema(&schema_for!(AllowanceResponse), &out_dir);
+    export_schema(&schema_for!(AllAccountsResponse), &out_dir);
+    export_schema(&schema_for!(SharesResponse), &out_dir);
+    export_schema(&schema_for!(AssetsResponse), &out_dir);
+    export_schema(&schema_for!(PreviewWithdrawUstResponse), &out_dir);
+    export_schema(&schema_for!(PreviewWithdrawBLunaResponse), &out_dir);
 }
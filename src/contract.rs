@@ -1,47 +1,89 @@
 use crate::ContractError::{
-    DivideByZeroError, Insufficient, Invalidate, Locked, Paused, Unauthorized,
+    BidsBalanced, DepositCapReached, EpochModeDisabled, EpochModeEnabled, EpochNotEnded, EpochOpen,
+    GuardrailViolated, Insufficient, Invalidate, Locked, NoShares, Paused, SlippageExceeded,
+    Unauthorized, WithdrawFulfilled, WithdrawPending,
 };
 
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::Order::Ascending;
 use cosmwasm_std::{
-    attr, to_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, Deps, DepsMut, Env, Fraction,
-    MessageInfo, Order, Response, StdResult, Timestamp, Uint128, Uint256, WasmMsg,
+    attr, from_binary, to_binary, Addr, Attribute, BankMsg, Binary, Coin, CosmosMsg, Decimal,
+    Decimal256, Deps, DepsMut, Env, Fraction, MessageInfo, Order, Reply, Response, StdError,
+    StdResult, SubMsg, Timestamp, Uint128, Uint256, WasmMsg,
 };
 use cw2::set_contract_version;
-use cw_storage_plus::U32Key;
+use cw20::{Cw20ReceiveMsg, Expiration};
+use cw_storage_plus::{Bound, U32Key};
 use std::convert::{TryFrom, TryInto};
-use std::ops::Mul;
 use std::str::FromStr;
 
 use crate::error::ContractError;
+use crate::fees::{
+    accrue_fees, accrue_management_fee, accrue_state, fee_amount, performance_fee_shares,
+    settle_management_fee, share_price, BPS, MAX_MANAGEMENT_FEE, MAX_PERFORMANCE_FEE,
+    MAX_TRANSACTION_FEE,
+};
 use crate::msg::AssetInfo::{NativeToken, Token};
 use crate::msg::SwapOperation::{AstroSwap, NativeSwap};
 use crate::msg::{
-    ActivatableResponse, BalanceResponse, BidsResponse, ClaimableResponse, ConfigResponse,
-    Cw20BalanceResponse, ExecuteMsg, ExternalMsg, ExternalQueryMsg, InfoResponse, InstantiateMsg,
-    PermissionResponse, PriceResponse, QueryMsg, TimestampResponse, TotalCapResponse,
-    UnlockableResponse,
+    ActivatableResponse, AllAccountsResponse, AllowanceResponse, AllowlistResponse, AssetsResponse,
+    BalanceResponse, BidEntry, BidResponse, ClaimableResponse, ConfigResponse, Cw20HookMsg,
+    EpochRequestResponse, EpochResponse, ExecuteMsg, ExternalMsg, FeesResponse, InfoResponse,
+    InstantiateMsg, NavBreakdownResponse, NextBidsResponse, OracleStatusResponse,
+    PermissionResponse, PreviewWithdrawBLunaResponse, PreviewWithdrawUstResponse, QueryMsg,
+    RemainingCapacityResponse, SharesResponse, SlotSuggestion, StrategyQuery, SuggestSlotsResponse,
+    SurplusResponse, TimestampResponse, TokenInfoResponse, TotalCapResponse, UnlockableResponse,
+    VaultState, WithdrawQueueResponse, WithdrawReceiptResponse, WithdrawTicketResponse,
+    WithdrawTicketsResponse,
 };
+use crate::oracle::{check_price, price_age, price_in_bounds, query_price};
 use crate::state::{
-    Permission, State, TokenRecord, BALANCES, CLAIM_LIST, LAST_DEPOSIT, PERMISSIONS, STATE,
+    BidRetraction, BidTarget, DepositMode, Epoch, EpochRequest, Permission, PriceAggregation,
+    PriceSource, State, TokenRecord, WithdrawReceipt, WithdrawTicket, ZapDeposit, ALLOWANCES,
+    ALLOWLIST, BALANCES, BID_RETRACTION, CLAIM_LIST, EPOCHS, EPOCH_REQUESTS, LAST_DEPOSIT,
+    LOCKED_SHARES, PERMISSIONS, STATE, USER_TICKETS, UST_SNAPSHOT, WITHDRAW_QUEUE,
+    WITHDRAW_RECEIPTS, WITHDRAW_TICKETS, ZAP_DEPOSIT,
+};
+use crate::valuation::{
+    convert_to_assets, convert_to_shares, query_b_luna_balance, query_bid_pools, query_bids,
+    query_collateral_info, query_valuation, VaultValuation, DEAD_SHARES,
 };
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:terra-deposit-withdraw";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// share token info
+const TOKEN_NAME: &str = "Kallisto bLuna Liquidation Pool";
+const TOKEN_SYMBOL: &str = "KBLP";
+const TOKEN_DECIMALS: u8 = 6;
+
+// Highest premium slot of Anchor liquidation queue
+const MAX_PREMIUM_SLOT: u8 = 30;
+
+// pagination
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+// reply ids
+const SWAP_REPLY_ID: u64 = 1;
+const ZAP_REPLY_ID: u64 = 2;
+const RETRACT_REPLY_ID: u64 = 3;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
+#[allow(clippy::unnecessary_to_owned)]
 pub fn instantiate(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
     let state = State {
         owner: msg.owner.clone(),
         total_supply: Uint128::zero(),
+        idle_ust: Uint128::zero(),
+        free_b_luna: Uint128::zero(),
         locked_b_luna: Uint128::zero(),
         swap_wallet: msg.swap_wallet.clone(),
         paused: false,
@@ -59,7 +101,51 @@ pub fn instantiate(
             .unwrap_or_else(|| Addr::unchecked("terra16t7dpwwgx9n3lq6l6te3753lsjqwhxwpday9zx")),
         lock_period: msg.lock_period.unwrap_or(14 * 24 * 60 * 60),
         withdraw_lock: msg.withdraw_lock.unwrap_or(60 * 60),
+        max_price_age: msg.max_price_age.unwrap_or(10 * 60),
+        min_price: msg.min_price.unwrap_or_else(Decimal256::zero),
+        max_price: msg.max_price.unwrap_or(Decimal256::MAX),
+        price_sources: validate_price_sources(
+            deps.as_ref(),
+            msg.price_sources.unwrap_or_default(),
+        )?,
+        price_aggregation: msg.price_aggregation.unwrap_or(PriceAggregation::Fallback),
+        performance_fee: msg.performance_fee.unwrap_or(0),
+        fee_recipient: msg.fee_recipient.unwrap_or(msg.owner.clone()),
+        high_water_mark: Decimal256::one(),
+        accrued_fees: Uint128::zero(),
+        management_fee: msg.management_fee.unwrap_or(0),
+        last_fee_accrual: env.block.time.seconds(),
+        accrued_management_fees: Uint128::zero(),
+        deposit_fee: msg.deposit_fee.unwrap_or(0),
+        withdraw_fee: msg.withdraw_fee.unwrap_or(0),
+        insurance_reserve: Uint128::zero(),
+        insurance_reserve_b_luna: Uint128::zero(),
+        max_total_cap: msg.max_total_cap.unwrap_or(Uint128::MAX),
+        max_user_deposit: msg.max_user_deposit.unwrap_or(Uint128::MAX),
+        deposit_mode: msg.deposit_mode.unwrap_or(DepositMode::Open),
+        epoch_mode: msg.epoch_mode.unwrap_or(false),
+        epoch_length: msg.epoch_length.unwrap_or(24 * 60 * 60),
+        current_epoch: 0,
+        epoch_start: env.block.time.seconds(),
+        epoch_ust: Uint128::zero(),
+        bid_reserve: msg.bid_reserve.unwrap_or_default(),
+        bid_targets: validate_bid_targets(msg.bid_targets.unwrap_or_default())?,
+        strategy: msg
+            .strategy
+            .map(|strategy| deps.api.addr_validate(&strategy.to_string().to_lowercase()))
+            .transpose()?,
+        strategy_slots: validate_premium_slots(msg.strategy_slots.unwrap_or_default())?,
+        max_slot_amount: msg.max_slot_amount.unwrap_or(Uint128::MAX),
+        min_rebalance_delta: msg.min_rebalance_delta.unwrap_or_default(),
     };
+    if state.min_price > state.max_price
+        || state.performance_fee > MAX_PERFORMANCE_FEE
+        || state.management_fee > MAX_MANAGEMENT_FEE
+        || state.deposit_fee > MAX_TRANSACTION_FEE
+        || state.withdraw_fee > MAX_TRANSACTION_FEE
+    {
+        return Err(Invalidate {});
+    }
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     STATE.save(deps.storage, &state)?;
     PERMISSIONS.save(
@@ -77,18 +163,56 @@ pub fn instantiate(
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
+    // Charge management fee for elapsed time at old supply and fee
+    settle_management_fee(deps.branch(), &env)?;
     match msg {
         // Deposit UST to vault
-        ExecuteMsg::Deposit {} => deposit(deps, env, info),
+        ExecuteMsg::Deposit {
+            min_shares,
+            recipient,
+        } => deposit(deps, env, info, min_shares, recipient),
+        // Deposit native coin swapped to UST
+        ExecuteMsg::ZapDeposit {
+            minimum_receive,
+            min_shares,
+            recipient,
+        } => zap_deposit(deps, env, info, minimum_receive, min_shares, recipient),
+        // Deposit bLuna sent from collateral token
+        ExecuteMsg::Receive(msg) => receive(deps, env, info, msg),
         // Withdraw UST from vault
-        ExecuteMsg::WithdrawUst { share } => withdraw_ust(deps, env, info, share),
+        ExecuteMsg::WithdrawUst {
+            share,
+            min_amount,
+            recipient,
+        } => withdraw_ust(deps, env, info, share, min_amount, recipient),
         // Withdraw bLuna from Vault
-        ExecuteMsg::WithdrawBLuna { share } => withdraw_b_luna(deps, env, info, share),
+        ExecuteMsg::WithdrawBLuna {
+            share,
+            min_amount,
+            recipient,
+        } => withdraw_b_luna(deps, env, info, share, min_amount, recipient),
+        // Withdraw slice of liquid assets
+        ExecuteMsg::WithdrawProRata { share, recipient } => {
+            withdraw_pro_rata(deps, env, info, share, recipient)
+        }
+        ExecuteMsg::ClaimReceipt {} => claim_receipt(deps, env, info),
+        // Queue shares to withdraw UST without retracting bids
+        ExecuteMsg::RequestWithdraw { share } => request_withdraw(deps, env, info, share),
+        // Only permissioned address can fulfil queued tickets from idle UST
+        ExecuteMsg::FulfillWithdraw { limit } => fulfill_withdraw(deps, env, info, limit),
+        ExecuteMsg::ClaimWithdraw { ticket_id } => claim_withdraw(deps, info, ticket_id),
+        ExecuteMsg::CancelWithdraw { ticket_id } => cancel_withdraw(deps, info, ticket_id),
+        // Batch deposits and redemptions in epoch mode
+        ExecuteMsg::RequestDeposit {} => request_deposit(deps, env, info),
+        ExecuteMsg::RequestRedeem { share } => request_redeem(deps, env, info, share),
+        // Only permissioned address can execute
+        ExecuteMsg::CloseEpoch {} => close_epoch(deps, env, info),
+        ExecuteMsg::ClaimEpoch { epoch_id } => claim_epoch(deps, info, epoch_id),
         // Activate all bids
         ExecuteMsg::ActivateBid {} => activate_bid(deps, env, info),
         // Submit bid with amount and premium slot from service
@@ -97,6 +221,19 @@ pub fn execute(
             amount,
             premium_slot,
         } => submit_bid(deps, env, info, amount, premium_slot),
+        // Submit bids of several premium slots at once
+        // Only permissioned address can execute
+        ExecuteMsg::SubmitBids { bids } => submit_bids(deps, env, info, bids),
+        // Only permissioned address can execute
+        ExecuteMsg::RetractBid { bid_idx, amount } => retract_bid(deps, env, info, bid_idx, amount),
+        // Only permissioned address can execute
+        ExecuteMsg::RebalanceBids {
+            from_slot,
+            to_slot,
+            amount,
+        } => rebalance_bids(deps, env, info, from_slot, to_slot, amount),
+        // Move bids towards bid targets, anyone can execute
+        ExecuteMsg::Rebalance {} => rebalance(deps, env, info),
         // Withdraw all liquidated bLuna from Anchor
         ExecuteMsg::ClaimLiquidation {} => claim_liquidation(deps, env, info),
         ExecuteMsg::Unlock {} => unlock(deps, env, info),
@@ -111,6 +248,28 @@ pub fn execute(
             swap_wallet,
             lock_period,
             withdraw_lock,
+            max_price_age,
+            min_price,
+            max_price,
+            price_sources,
+            price_aggregation,
+            performance_fee,
+            fee_recipient,
+            management_fee,
+            deposit_fee,
+            withdraw_fee,
+            max_total_cap,
+            max_user_deposit,
+            deposit_mode,
+            epoch_mode,
+            epoch_length,
+            bid_reserve,
+            bid_targets,
+            strategy,
+            clear_strategy,
+            strategy_slots,
+            max_slot_amount,
+            min_rebalance_delta,
         } => update_config(
             deps,
             info,
@@ -119,122 +278,433 @@ pub fn execute(
             swap_wallet,
             lock_period,
             withdraw_lock,
+            max_price_age,
+            min_price,
+            max_price,
+            price_sources,
+            price_aggregation,
+            performance_fee,
+            fee_recipient,
+            management_fee,
+            deposit_fee,
+            withdraw_fee,
+            max_total_cap,
+            max_user_deposit,
+            deposit_mode,
+            epoch_mode,
+            epoch_length,
+            bid_reserve,
+            bid_targets,
+            strategy,
+            clear_strategy,
+            strategy_slots,
+            max_slot_amount,
+            min_rebalance_delta,
         ),
+        // Only owner can execute
+        ExecuteMsg::UpdateAllowlist { add, remove } => update_allowlist(deps, info, add, remove),
+        // Move insurance reserve into vault assets
+        // Only owner can execute
+        ExecuteMsg::CoverLoss {} => cover_loss(deps, info),
+        // Accrue performance fee, anyone can execute
+        ExecuteMsg::HarvestFees {} => harvest_fees(deps, env, info),
+        // Only permissioned address can execute
+        ExecuteMsg::Sync {} => sync(deps, env, info),
+        // Only owner can execute
+        ExecuteMsg::Skim { recipient } => skim(deps, env, info, recipient),
+        // Transfer shares to another address
+        ExecuteMsg::Transfer { recipient, amount } => transfer(deps, env, info, recipient, amount),
+        // Transfer shares to a contract and trigger its Receive hook
+        ExecuteMsg::Send {
+            contract,
+            amount,
+            msg,
+        } => send(deps, env, info, contract, amount, msg),
+        ExecuteMsg::IncreaseAllowance {
+            spender,
+            amount,
+            expires,
+        } => increase_allowance(deps, env, info, spender, amount, expires),
+        ExecuteMsg::DecreaseAllowance {
+            spender,
+            amount,
+            expires,
+        } => decrease_allowance(deps, env, info, spender, amount, expires),
+        // Transfer shares of owner using allowance
+        ExecuteMsg::TransferFrom {
+            owner,
+            recipient,
+            amount,
+        } => transfer_from(deps, env, info, owner, recipient, amount),
+        // Burn shares of owner using allowance
+        ExecuteMsg::BurnFrom { owner, amount } => burn_from(deps, env, info, owner, amount),
     }
 }
 
-fn deposit(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+fn deposit(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    min_shares: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
     // Only one coin
     if info.funds.len() != 1 {
         return Err(Invalidate {});
     }
-    let mut share: Uint128 = info.funds[0].amount;
+    let amount: Uint128 = info.funds[0].amount;
     // Only UST and non-zero amount
-    if info.funds[0].denom != "uusd" || share.is_zero() {
+    if info.funds[0].denom != "uusd" || amount.is_zero() {
         return Err(Invalidate {});
     }
     let mut state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
     if state.paused {
         return Err(Paused {});
     }
     let msg_sender = info.sender.to_string().to_lowercase();
-    LAST_DEPOSIT.save(
-        deps.storage,
-        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
-        &env.block.time,
+    let (recipient, plan) = mint_deposit_shares(
+        deps.branch(),
+        &env,
+        &mut state,
+        &msg_sender,
+        recipient,
+        amount,
+        min_shares,
     )?;
-    // UST in vault
-    let mut usd_balance = deps
-        .querier
-        .query_balance(&env.contract.address, "uusd")?
-        .amount
-        - share;
-    // bLuna in vault
-    let b_luna_balance_response: Cw20BalanceResponse = deps.querier.query_wasm_smart(
-        state.collateral_token.to_string(),
-        &ExternalQueryMsg::Balance {
-            address: env.contract.address.to_string(),
-        },
+    state.idle_ust += amount - plan.fee;
+    state.insurance_reserve += plan.fee;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "deposit"),
+        attr("from", info.sender),
+        attr("to", recipient),
+        attr("amount", amount),
+        attr("share", plan.share),
+        attr("fee", plan.fee),
+    ]))
+}
+
+// Mint shares to recipient for deposit of UST value, recipient is sender by default
+// Caller books deposited assets and saves state
+fn mint_deposit_shares(
+    mut deps: DepsMut,
+    env: &Env,
+    state: &mut State,
+    sender: &str,
+    recipient: Option<String>,
+    value: Uint128,
+    min_shares: Option<Uint128>,
+) -> Result<(Addr, DepositPlan), ContractError> {
+    let recipient = deps.api.addr_validate(
+        &recipient
+            .unwrap_or_else(|| sender.to_string())
+            .to_lowercase(),
     )?;
-    let mut b_luna_balance = b_luna_balance_response.balance;
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    // Iterate all valid bids
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
+    let recipient_raw = deps.api.addr_canonicalize(recipient.as_str())?;
+    if !is_allowed(deps.as_ref(), state, recipient_raw.as_slice())? {
+        return Err(Unauthorized {});
+    }
+    let balance = BALANCES
+        .may_load(deps.storage, recipient_raw.as_slice())?
+        .unwrap_or_default();
+    // Deposit from third party does not reset withdraw lock of existing holder,
+    // only its credited shares are locked
+    let third_party = recipient.as_str() != sender && !balance.is_zero();
+    if !third_party {
+        LAST_DEPOSIT.save(deps.storage, recipient_raw.as_slice(), &env.block.time)?;
+    }
+    accrue_fees(deps.branch(), env, state)?;
+    let plan = plan_deposit(deps.as_ref(), env, state, value)?;
+    check_slippage(min_shares, plan.share)?;
+    if third_party {
+        let locked = locked_share(deps.as_ref(), env, state, recipient_raw.as_slice())?;
+        LOCKED_SHARES.save(
+            deps.storage,
+            recipient_raw.as_slice(),
+            &TokenRecord {
+                amount: locked + plan.share,
+                timestamp: env.block.time,
             },
         )?;
-        for item in &res.bids {
-            // Waiting UST for liquidation
-            usd_balance += Uint128::try_from(item.amount)?;
-            // Pending bLuna in Anchor
-            b_luna_balance += Uint128::try_from(item.pending_liquidated_collateral)?;
-        }
-        if res.bids.len() < 31 {
-            break;
-        }
-        start_after = Some(res.bids.last().unwrap().idx);
     }
-    // Fetch bLuna price from oracle
-    let price_response: PriceResponse = deps.querier.query_wasm_smart(
-        state.price_oracle.to_string(),
-        &ExternalQueryMsg::Price {
-            base: state.collateral_token.to_string(),
-            quote: "uusd".to_string(),
-        },
+    let (total_remaining, user_remaining) = remaining_capacity(state, plan.total_cap, balance);
+    let remaining = total_remaining.min(user_remaining);
+    if value - plan.fee > remaining {
+        return Err(DepositCapReached { remaining });
+    }
+    mint_plan_shares(deps.branch(), env, state, &plan)?;
+    BALANCES.save(
+        deps.storage,
+        recipient_raw.as_slice(),
+        &(balance + plan.share),
     )?;
-    let price = price_response.rate;
-    let total_cap = Uint128::try_from(Uint256::from(b_luna_balance).mul(price))? + usd_balance;
-    if !state.total_supply.is_zero() {
-        if total_cap.is_zero() {
-            return Err(DivideByZeroError {});
-        }
-        share = share.checked_mul(state.total_supply)? / total_cap;
+    Ok((recipient, plan))
+}
+
+// Add shares of deposit plan to total supply, dead share is held by the contract
+// Caller credits plan share to its holder
+fn mint_plan_shares(
+    deps: DepsMut,
+    env: &Env,
+    state: &mut State,
+    plan: &DepositPlan,
+) -> StdResult<()> {
+    state.total_supply += plan.share + plan.dead_share;
+    if !plan.dead_share.is_zero() {
+        BALANCES.save(
+            deps.storage,
+            deps.api
+                .addr_canonicalize(env.contract.address.as_str())?
+                .as_slice(),
+            &plan.dead_share,
+        )?;
     }
-    state.total_supply += share;
-    STATE.save(deps.storage, &state)?;
-    BALANCES.update(
+    Ok(())
+}
+
+fn zap_deposit(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    minimum_receive: Uint128,
+    min_shares: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    // Only one native coin other than UST, use Deposit for UST
+    if info.funds.len() != 1 {
+        return Err(Invalidate {});
+    }
+    let coin = info.funds[0].clone();
+    if coin.denom == "uusd" || coin.amount.is_zero() {
+        return Err(Invalidate {});
+    }
+    let state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    if state.paused {
+        return Err(Paused {});
+    }
+    // Received UST is deposited in reply
+    let ust_snapshot = deps
+        .querier
+        .query_balance(&env.contract.address, "uusd")?
+        .amount;
+    ZAP_DEPOSIT.save(
         deps.storage,
-        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
-        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default() + share) },
+        &ZapDeposit {
+            sender: info.sender.clone(),
+            recipient,
+            min_shares,
+            ust_snapshot,
+        },
+    )?;
+    let msg = ExternalMsg::ExecuteSwapOperations {
+        operations: vec![NativeSwap {
+            offer_denom: coin.denom.clone(),
+            ask_denom: "uusd".to_string(),
+        }],
+        minimum_receive: Some(minimum_receive),
+        to: None,
+        max_spread: None,
+    };
+    Ok(Response::new()
+        .add_submessage(SubMsg::reply_on_success(
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: state.astroport_router.to_string(),
+                msg: to_binary(&msg)?,
+                funds: vec![coin.clone()],
+            }),
+            ZAP_REPLY_ID,
+        ))
+        .add_attributes(vec![
+            attr("action", "zap_deposit"),
+            attr("from", info.sender),
+            attr("offer_amount", coin.amount),
+            attr("offer_denom", coin.denom),
+        ]))
+}
+
+fn zap_reply(mut deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let zap = ZAP_DEPOSIT.load(deps.storage)?;
+    ZAP_DEPOSIT.remove(deps.storage);
+    let ust_balance = deps
+        .querier
+        .query_balance(&env.contract.address, "uusd")?
+        .amount;
+    let amount = ust_balance.checked_sub(zap.ust_snapshot)?;
+    if amount.is_zero() {
+        return Err(Insufficient {});
+    }
+    let mut state = STATE.load(deps.storage)?;
+    let msg_sender = zap.sender.to_string().to_lowercase();
+    let (recipient, plan) = mint_deposit_shares(
+        deps.branch(),
+        &env,
+        &mut state,
+        &msg_sender,
+        zap.recipient,
+        amount,
+        zap.min_shares,
     )?;
+    state.idle_ust += amount - plan.fee;
+    state.insurance_reserve += plan.fee;
+    STATE.save(deps.storage, &state)?;
     Ok(Response::new().add_attributes(vec![
         attr("action", "deposit"),
-        attr("from", info.sender),
-        attr("amount", info.funds[0].amount),
-        attr("share", share),
+        attr("from", msg_sender),
+        attr("to", recipient),
+        attr("amount", amount),
+        attr("share", plan.share),
+        attr("fee", plan.fee),
     ]))
 }
 
-fn submit_bid(
+fn receive(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
+    msg: Cw20ReceiveMsg,
+) -> Result<Response, ContractError> {
+    match from_binary(&msg.msg)? {
+        Cw20HookMsg::DepositBLuna {
+            min_shares,
+            recipient,
+        } => deposit_b_luna(
+            deps, env, info, msg.sender, msg.amount, min_shares, recipient,
+        ),
+    }
+}
+
+fn deposit_b_luna(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    sender: String,
     amount: Uint128,
-    premium_slot: u8,
+    min_shares: Option<Uint128>,
+    recipient: Option<String>,
 ) -> Result<Response, ContractError> {
-    let permission = PERMISSIONS
-        .may_load(
-            deps.storage,
-            deps.api
-                .addr_canonicalize(info.sender.to_string().to_lowercase().as_str())?
-                .as_slice(),
-        )?
-        .unwrap_or(Permission { submit_bid: false });
-    if !permission.submit_bid {
+    let mut state = STATE.load(deps.storage)?;
+    // Only bLuna sent from collateral token
+    if info.sender.to_string().to_lowercase() != state.collateral_token.to_string().to_lowercase() {
         return Err(Unauthorized {});
     }
-    let usd_balance = deps
-        .querier
-        .query_balance(env.contract.address, "uusd")?
-        .amount;
-    if !amount.is_zero() && usd_balance >= amount {
-        let state = STATE.load(deps.storage)?;
+    if amount.is_zero() {
+        return Err(Invalidate {});
+    }
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    if state.paused {
+        return Err(Paused {});
+    }
+    // bLuna is valued at the price used for total cap
+    let price = query_price(deps.as_ref(), &env, &state)?;
+    check_price(&env, &state, price.rate, price.last_updated)?;
+    let value = Uint128::try_from(Uint256::from(amount) * price.rate)?;
+    let msg_sender = sender.to_lowercase();
+    let (recipient, plan) = mint_deposit_shares(
+        deps.branch(),
+        &env,
+        &mut state,
+        &msg_sender,
+        recipient,
+        value,
+        min_shares,
+    )?;
+    let fee = fee_amount(amount, state.deposit_fee);
+    state.free_b_luna += amount - fee;
+    state.insurance_reserve_b_luna += fee;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "deposit_b_luna"),
+        attr("from", msg_sender),
+        attr("to", recipient),
+        attr("amount", amount),
+        attr("value", value),
+        attr("share", plan.share),
+        attr("fee", fee),
+    ]))
+}
+
+// Fail if actual amount is less than minimum amount expected by user
+fn check_slippage(expected: Option<Uint128>, actual: Uint128) -> Result<(), ContractError> {
+    if let Some(expected) = expected {
+        if actual < expected {
+            return Err(SlippageExceeded { expected, actual });
+        }
+    }
+    Ok(())
+}
+
+// Result of deposit calculation
+struct DepositPlan {
+    // Share minted to depositor
+    share: Uint128,
+    // Share minted to vault on first deposit
+    dead_share: Uint128,
+    // UST moved to insurance reserve
+    fee: Uint128,
+    // Total cap before deposit
+    total_cap: Uint128,
+}
+
+fn plan_deposit(
+    deps: Deps,
+    env: &Env,
+    state: &State,
+    amount: Uint128,
+) -> Result<DepositPlan, ContractError> {
+    let valuation = query_valuation(deps, env, state)?;
+    valuation.check_price(env, state)?;
+    let fee = fee_amount(amount, state.deposit_fee);
+    let total_cap = valuation.total_cap()?;
+    let share = convert_to_shares(amount - fee, total_cap, state.total_supply);
+    let dead_share = if state.total_supply.is_zero() {
+        Uint128::from(DEAD_SHARES)
+    } else {
+        Uint128::zero()
+    };
+    // Deposit too small to mint any share
+    if share <= dead_share {
+        return Err(Insufficient {});
+    }
+    Ok(DepositPlan {
+        share: share - dead_share,
+        dead_share,
+        fee,
+        total_cap,
+    })
+}
+
+// UST that can be added to total cap until max total cap and until max user deposit
+fn remaining_capacity(state: &State, total_cap: Uint128, balance: Uint128) -> (Uint128, Uint128) {
+    let user_deposit = convert_to_assets(balance, total_cap, state.total_supply);
+    (
+        state.max_total_cap.saturating_sub(total_cap),
+        state.max_user_deposit.saturating_sub(user_deposit),
+    )
+}
+
+fn submit_bid(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+    premium_slot: u8,
+) -> Result<Response, ContractError> {
+    check_permission(deps.as_ref(), &info)?;
+    let mut state = STATE.load(deps.storage)?;
+    if premium_slot > MAX_PREMIUM_SLOT {
+        return Err(Invalidate {});
+    }
+    if !amount.is_zero() && biddable_ust(&state) >= amount {
+        state.idle_ust -= amount;
+        STATE.save(deps.storage, &state)?;
         Ok(Response::new()
             .add_attributes(vec![
                 attr("action", "submit_bid"),
@@ -242,444 +712,1499 @@ fn submit_bid(
                 attr("amount", amount),
                 attr("premium_slot", premium_slot.to_string()),
             ])
-            .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: state.anchor_liquidation_queue.to_string(),
-                funds: vec![Coin::new(amount.u128(), "uusd")],
-                msg: to_binary(&ExternalMsg::SubmitBid {
-                    collateral_token: state.collateral_token.to_string(),
-                    premium_slot,
-                })?,
-            })))
+            .add_message(submit_bid_msg(&state, amount, premium_slot)?))
     } else {
         Err(Insufficient {})
     }
 }
 
-fn activate_bid(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    let mut bids_idx = Vec::new();
-    let state = STATE.load(deps.storage)?;
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
-        )?;
-        for item in &res.bids {
-            if let Some(wait_end) = item.wait_end {
-                if wait_end < env.block.time.seconds() {
-                    bids_idx.push(item.idx);
-                } else {
-                    break;
-                }
-            }
-        }
-        if res.bids.len() < 31 {
-            break;
+fn submit_bids(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    bids: Vec<BidEntry>,
+) -> Result<Response, ContractError> {
+    check_permission(deps.as_ref(), &info)?;
+    let mut state = STATE.load(deps.storage)?;
+    if bids.is_empty() {
+        return Err(Invalidate {});
+    }
+    let mut slots = vec![];
+    let mut total_amount = Uint128::zero();
+    for bid in bids.iter() {
+        if bid.amount.is_zero()
+            || bid.premium_slot > MAX_PREMIUM_SLOT
+            || slots.contains(&bid.premium_slot)
+        {
+            return Err(Invalidate {});
         }
-        start_after = Some(res.bids.last().unwrap().idx);
+        slots.push(bid.premium_slot);
+        total_amount += bid.amount;
+    }
+    if biddable_ust(&state) < total_amount {
+        return Err(Insufficient {});
+    }
+    state.idle_ust -= total_amount;
+    STATE.save(deps.storage, &state)?;
+    let mut attributes = vec![
+        attr("action", "submit_bids"),
+        attr("from", info.sender),
+        attr("amount", total_amount),
+    ];
+    let mut messages = vec![];
+    for bid in bids {
+        attributes.push(attr(
+            format!("premium_slot_{}", bid.premium_slot),
+            bid.amount,
+        ));
+        messages.push(submit_bid_msg(&state, bid.amount, bid.premium_slot)?);
     }
     Ok(Response::new()
-        .add_attributes(vec![attr("action", "activate"), attr("from", info.sender)])
-        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: state.anchor_liquidation_queue.to_string(),
-            funds: vec![],
-            msg: to_binary(&ExternalMsg::ActivateBids {
-                collateral_token: state.collateral_token.to_string(),
-                bids_idx: Some(bids_idx),
-            })?,
-        })))
+        .add_attributes(attributes)
+        .add_messages(messages))
 }
 
-fn withdraw_ust(
+// Idle UST over bid reserve
+fn biddable_ust(state: &State) -> Uint128 {
+    state.idle_ust.saturating_sub(state.bid_reserve)
+}
+
+fn submit_bid_msg(state: &State, amount: Uint128, premium_slot: u8) -> StdResult<CosmosMsg> {
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: state.anchor_liquidation_queue.to_string(),
+        funds: vec![Coin::new(amount.u128(), "uusd")],
+        msg: to_binary(&ExternalMsg::SubmitBid {
+            collateral_token: state.collateral_token.to_string(),
+            premium_slot,
+        })?,
+    }))
+}
+
+fn retract_bid(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    share: Uint128,
+    bid_idx: Uint128,
+    amount: Option<Uint256>,
 ) -> Result<Response, ContractError> {
-    if share.is_zero() {
+    check_permission(deps.as_ref(), &info)?;
+    let state = STATE.load(deps.storage)?;
+    let bid = query_bids(deps.as_ref(), &env, &state)?
+        .into_iter()
+        .find(|bid| bid.idx == bid_idx)
+        .ok_or(Invalidate {})?;
+    let retract_amount = amount.unwrap_or(bid.amount);
+    if retract_amount.is_zero() || retract_amount > bid.amount {
         return Err(Invalidate {});
     }
-    let msg_sender = info.sender.to_string().to_lowercase();
-    let last_timestamp = LAST_DEPOSIT.may_load(
-        deps.storage,
-        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
+    let retract_amount = Uint128::try_from(retract_amount)?;
+    let msgs = retract_bid_msgs(
+        deps,
+        &env,
+        &state,
+        vec![(bid_idx, amount)],
+        retract_amount,
+        vec![],
     )?;
-    let mut state = STATE.load(deps.storage)?;
-    if let Some(timestamp) = last_timestamp {
-        if timestamp.plus_seconds(state.withdraw_lock) >= env.block.time {
-            return Err(Locked {});
-        }
+    Ok(Response::new().add_submessages(msgs).add_attributes(vec![
+        attr("action", "retract_bid"),
+        attr("from", info.sender),
+        attr("bid_idx", bid_idx),
+        attr("amount", retract_amount),
+    ]))
+}
+
+fn rebalance_bids(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from_slot: u8,
+    to_slot: u8,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    check_permission(deps.as_ref(), &info)?;
+    let state = STATE.load(deps.storage)?;
+    if amount.is_zero()
+        || from_slot == to_slot
+        || from_slot > MAX_PREMIUM_SLOT
+        || to_slot > MAX_PREMIUM_SLOT
+    {
+        return Err(Invalidate {});
     }
-    BALANCES.update(
-        deps.storage,
-        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
-        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default().checked_sub(share)?) },
-    )?;
-    let uusd_balance = deps
-        .querier
-        .query_balance(&env.contract.address, "uusd")?
-        .amount;
-    let mut usd_balance = uusd_balance;
-    let b_luna_balance_response: Cw20BalanceResponse = deps.querier.query_wasm_smart(
-        state.collateral_token.to_string(),
-        &ExternalQueryMsg::Balance {
-            address: env.contract.address.to_string(),
-        },
-    )?;
-    let mut b_luna_balance = b_luna_balance_response.balance;
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
-        )?;
-        for item in &res.bids {
-            usd_balance += Uint128::try_from(item.amount)?;
-            b_luna_balance += Uint128::try_from(item.pending_liquidated_collateral)?;
-        }
-        if res.bids.len() < 31 {
+    // Retract bids of from slot in order until amount is covered
+    let mut retract_bids = vec![];
+    let mut remaining = Uint256::from(amount);
+    for bid in query_bids(deps.as_ref(), &env, &state)? {
+        if remaining.is_zero() {
             break;
         }
-        start_after = Some(res.bids.last().unwrap().idx);
+        if bid.premium_slot != from_slot || bid.amount.is_zero() {
+            continue;
+        }
+        if bid.amount <= remaining {
+            remaining -= bid.amount;
+            retract_bids.push((bid.idx, None));
+        } else {
+            retract_bids.push((bid.idx, Some(remaining)));
+            remaining = Uint256::zero();
+        }
     }
-    let price_response: PriceResponse = deps.querier.query_wasm_smart(
-        state.price_oracle.to_string(),
-        &ExternalQueryMsg::Price {
-            base: state.collateral_token.to_string(),
-            quote: "uusd".to_string(),
-        },
+    if !remaining.is_zero() {
+        return Err(Insufficient {});
+    }
+    let msgs = retract_bid_msgs(
+        deps,
+        &env,
+        &state,
+        retract_bids,
+        amount,
+        vec![(to_slot, amount)],
     )?;
-    let price = price_response.rate;
-    // Calculate total cap
-    let total_cap = Uint128::try_from(Uint256::from(b_luna_balance).mul(price))? + usd_balance;
+    Ok(Response::new().add_submessages(msgs).add_attributes(vec![
+        attr("action", "rebalance_bids"),
+        attr("from", info.sender),
+        attr("from_slot", from_slot.to_string()),
+        attr("to_slot", to_slot.to_string()),
+        attr("amount", amount),
+    ]))
+}
 
-    // Calculate exact amount from share and total cap
-    let withdraw_cap = total_cap * share / state.total_supply;
-    if withdraw_cap.is_zero() {
-        return Err(Insufficient {});
+// Retract bids over targets and submit bids under them
+// Targets come from strategy contract if set, from bid targets otherwise
+// Submitted bids are funded by idle UST over bid reserve and retracted UST
+// Premium slots within min rebalance delta of target are skipped
+fn rebalance(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage)?;
+    let bids = query_bids(deps.as_ref(), &env, &state)?;
+    let biddable = biddable_ust(&state);
+    let targets = match &state.strategy {
+        Some(strategy) => query_strategy_targets(deps.as_ref(), &state, strategy, &bids, biddable)?,
+        None => bid_target_amounts(&state, &bids, biddable)?,
+    };
+    // Bids of premium slots without target are retracted
+    let mut slots: Vec<u8> = targets
+        .iter()
+        .map(|(premium_slot, _)| *premium_slot)
+        .collect();
+    for bid in bids.iter() {
+        if !slots.contains(&bid.premium_slot) {
+            slots.push(bid.premium_slot);
+        }
     }
-    // Withdraw if UST in vault is enough
-    if uusd_balance >= withdraw_cap {
-        state.total_supply -= share;
-        STATE.save(deps.storage, &state)?;
-        Ok(Response::new()
-            .add_message(CosmosMsg::Bank(BankMsg::Send {
-                to_address: msg_sender,
-                amount: vec![Coin {
-                    denom: "uusd".to_string(),
-                    amount: withdraw_cap,
-                }],
-            }))
-            .add_attributes(vec![
-                attr("action", "withdraw"),
-                attr("to", info.sender),
-                attr("share", share),
-                attr("amount", withdraw_cap),
-            ]))
-    } else {
-        // Retract bids for insufficient UST in vault
-        let mut messages = vec![];
-        usd_balance = withdraw_cap - uusd_balance;
-        start_after = Some(Uint128::zero());
-        loop {
-            let res: BidsResponse = deps.querier.query_wasm_smart(
-                state.anchor_liquidation_queue.to_string(),
-                &ExternalQueryMsg::BidsByUser {
-                    collateral_token: state.collateral_token.to_string(),
-                    bidder: env.contract.address.to_string(),
-                    start_after,
-                    limit: Some(31),
-                },
-            )?;
-            for item in &res.bids {
-                if !item.amount.is_zero() {
-                    if item.amount < usd_balance.into() {
-                        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-                            contract_addr: state.anchor_liquidation_queue.to_string(),
-                            msg: to_binary(&ExternalMsg::RetractBid {
-                                bid_idx: item.idx,
-                                amount: None,
-                            })?,
-                            funds: vec![],
-                        }));
-                        usd_balance -= Uint128::try_from(item.amount)?;
-                    } else {
-                        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-                            contract_addr: state.anchor_liquidation_queue.to_string(),
-                            msg: to_binary(&ExternalMsg::RetractBid {
-                                bid_idx: item.idx,
-                                amount: Some(usd_balance.into()),
-                            })?,
-                            funds: vec![],
-                        }));
-                        usd_balance = Uint128::zero();
-                        break;
-                    }
-                }
-            }
-            if usd_balance.is_zero() || res.bids.len() < 31 {
+    let mut retract_bids = vec![];
+    let mut retract_amount = Uint128::zero();
+    let mut shortfalls = vec![];
+    for premium_slot in slots {
+        let target = targets
+            .iter()
+            .find(|(slot, _)| *slot == premium_slot)
+            .map(|(_, amount)| Uint256::from(*amount))
+            .unwrap_or_default();
+        let mut current = Uint256::zero();
+        for bid in bids.iter().filter(|bid| bid.premium_slot == premium_slot) {
+            current += bid.amount;
+        }
+        // Differences under min rebalance delta are left as they are
+        let delta = if current < target {
+            target - current
+        } else {
+            current - target
+        };
+        if delta < Uint256::from(state.min_rebalance_delta) {
+            continue;
+        }
+        if current < target {
+            shortfalls.push((premium_slot, Uint128::try_from(target - current)?));
+            continue;
+        }
+        let mut excess = current - target;
+        for bid in bids.iter().filter(|bid| bid.premium_slot == premium_slot) {
+            if excess.is_zero() {
                 break;
             }
-            start_after = Some(res.bids.last().unwrap().idx);
-        }
-        if withdraw_cap > usd_balance {
-            messages.push(CosmosMsg::Bank(BankMsg::Send {
-                to_address: msg_sender.clone(),
-                amount: vec![Coin {
-                    denom: "uusd".to_string(),
-                    amount: withdraw_cap - usd_balance,
-                }],
-            }));
-        }
-        let mut unlocked_b_luna = Uint128::zero();
-        if !usd_balance.is_zero() {
-            let mut b_luna_withdraw = b_luna_balance * share * usd_balance
-                / withdraw_cap
-                / (state.total_supply - share * (withdraw_cap - usd_balance) / withdraw_cap);
-            if !b_luna_withdraw.is_zero() {
-                // swap on wallet
-                messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-                    contract_addr: state.collateral_token.to_string(),
-                    msg: to_binary(&ExternalMsg::Send {
-                        contract: state.swap_wallet.to_string(),
-                        amount: b_luna_withdraw,
-                        msg: to_binary(&deps.api.addr_validate(&msg_sender)?)?,
-                    })?,
-                    funds: vec![],
-                }));
-                // unlock
-                if b_luna_balance_response.balance - state.locked_b_luna < b_luna_withdraw {
-                    b_luna_withdraw -= b_luna_balance_response.balance - state.locked_b_luna;
-                    let keys = CLAIM_LIST.keys(deps.storage, None, None, Order::Ascending);
-                    let mut remove_keys = Vec::new();
-                    let mut last_key = None;
-                    let mut new_claim = TokenRecord {
-                        amount: Uint128::zero(),
-                        timestamp: Timestamp::default(),
-                    };
-                    for key in keys {
-                        let claim = CLAIM_LIST.load(deps.storage, U32Key::from(key.clone()))?;
-                        if b_luna_withdraw >= claim.amount {
-                            b_luna_withdraw -= claim.amount;
-                            unlocked_b_luna += claim.amount;
-                            remove_keys.push(U32Key::from(key));
-                        } else {
-                            unlocked_b_luna += b_luna_withdraw;
-                            new_claim = claim.clone();
-                            new_claim.amount -= b_luna_withdraw;
-                            b_luna_withdraw = Uint128::zero();
-                            last_key = Some(key);
-                        }
-                        if b_luna_withdraw.is_zero() {
-                            break;
-                        }
-                    }
-                    for key in remove_keys {
-                        CLAIM_LIST.remove(deps.storage, key);
-                    }
-                    if let Some(key) = last_key {
-                        CLAIM_LIST.save(deps.storage, U32Key::from(key), &new_claim)?;
-                    }
-                    if !unlocked_b_luna.is_zero() {
-                        state.locked_b_luna -= unlocked_b_luna;
-                    }
-                }
+            if bid.amount.is_zero() {
+                continue;
+            }
+            if bid.amount <= excess {
+                excess -= bid.amount;
+                retract_amount += Uint128::try_from(bid.amount)?;
+                retract_bids.push((bid.idx, None));
+            } else {
+                retract_amount += Uint128::try_from(excess)?;
+                retract_bids.push((bid.idx, Some(excess)));
+                excess = Uint256::zero();
             }
         }
-        state.total_supply -= share;
-        STATE.save(deps.storage, &state)?;
-        let mut attrs = vec![
-            attr("action", "withdraw"),
-            attr("to", info.sender),
-            attr("share", share),
-            attr("amount", withdraw_cap),
-        ];
-        if !unlocked_b_luna.is_zero() {
-            attrs.push(attr("unlocked", unlocked_b_luna.to_string()));
+    }
+    // Shortfalls in target order while UST lasts
+    let mut available = biddable + retract_amount;
+    let mut submit_bids = vec![];
+    for (premium_slot, shortfall) in shortfalls {
+        let amount = shortfall.min(available);
+        if !amount.is_zero() {
+            available -= amount;
+            submit_bids.push((premium_slot, amount));
         }
-        Ok(Response::new().add_messages(messages).add_attributes(attrs))
     }
+    if retract_bids.is_empty() && submit_bids.is_empty() {
+        return Err(BidsBalanced {});
+    }
+    let mut res = Response::new().add_attributes(vec![
+        attr("action", "rebalance"),
+        attr("from", info.sender),
+        attr("retract_amount", retract_amount),
+    ]);
+    if retract_bids.is_empty() {
+        // Submit from idle UST only
+        for (premium_slot, amount) in submit_bids {
+            state.idle_ust -= amount;
+            res = res
+                .add_message(submit_bid_msg(&state, amount, premium_slot)?)
+                .add_attribute(format!("premium_slot_{}", premium_slot), amount);
+        }
+        STATE.save(deps.storage, &state)?;
+        return Ok(res);
+    }
+    let msgs = retract_bid_msgs(
+        deps,
+        &env,
+        &state,
+        retract_bids,
+        retract_amount,
+        submit_bids,
+    )?;
+    Ok(res.add_submessages(msgs))
 }
 
-fn withdraw_b_luna(
-    deps: DepsMut,
-    env: Env,
-    info: MessageInfo,
-    share: Uint128,
-) -> Result<Response, ContractError> {
-    if share.is_zero() {
+// Target UST per premium slot by bid targets
+// Weight of UST in bids and idle UST over bid reserve, clamped to min and max amount
+fn bid_target_amounts(
+    state: &State,
+    bids: &[BidResponse],
+    biddable: Uint128,
+) -> Result<Vec<(u8, Uint128)>, ContractError> {
+    if state.bid_targets.is_empty() {
         return Err(Invalidate {});
     }
-    let msg_sender = info.sender.to_string().to_lowercase();
-    let last_timestamp = LAST_DEPOSIT.may_load(
-        deps.storage,
-        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
-    )?;
-    let mut state = STATE.load(deps.storage)?;
-    if let Some(timestamp) = last_timestamp {
-        if timestamp.plus_seconds(state.withdraw_lock) >= env.block.time {
-            return Err(Locked {});
+    let mut total = biddable;
+    for bid in bids.iter() {
+        total += Uint128::try_from(bid.amount)?;
+    }
+    Ok(state
+        .bid_targets
+        .iter()
+        .map(|target| {
+            (
+                target.premium_slot,
+                total
+                    .multiply_ratio(target.weight, BPS)
+                    .max(target.min_amount)
+                    .min(target.max_amount),
+            )
+        })
+        .collect())
+}
+
+// Target UST per premium slot by strategy contract
+// Fails if targets are out of strategy slots or over max slot amount
+fn query_strategy_targets(
+    deps: Deps,
+    state: &State,
+    strategy: &Addr,
+    bids: &[BidResponse],
+    biddable: Uint128,
+) -> Result<Vec<(u8, Uint128)>, ContractError> {
+    let mut slot_bids: Vec<BidEntry> = vec![];
+    for bid in bids.iter() {
+        let amount = Uint128::try_from(bid.amount)?;
+        match slot_bids
+            .iter_mut()
+            .find(|entry| entry.premium_slot == bid.premium_slot)
+        {
+            Some(entry) => entry.amount += amount,
+            None => slot_bids.push(BidEntry {
+                premium_slot: bid.premium_slot,
+                amount,
+            }),
         }
     }
-    BALANCES.update(
-        deps.storage,
-        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
-        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default().checked_sub(share)?) },
+    let res: NextBidsResponse = deps.querier.query_wasm_smart(
+        strategy.to_string(),
+        &StrategyQuery::NextBids {
+            vault_state: VaultState {
+                biddable_ust: biddable,
+                bids: slot_bids,
+            },
+        },
     )?;
-    let uusd_balance = deps
+    let mut targets: Vec<(u8, Uint128)> = vec![];
+    for bid in res.bids {
+        if !state.strategy_slots.contains(&bid.premium_slot)
+            || bid.amount > state.max_slot_amount
+            || targets.iter().any(|(slot, _)| *slot == bid.premium_slot)
+        {
+            return Err(GuardrailViolated {});
+        }
+        targets.push((bid.premium_slot, bid.amount));
+    }
+    Ok(targets)
+}
+
+// Retract bid messages, last one replies to book retracted UST and submit resubmit bids
+fn retract_bid_msgs(
+    deps: DepsMut,
+    env: &Env,
+    state: &State,
+    retract_bids: Vec<(Uint128, Option<Uint256>)>,
+    amount: Uint128,
+    resubmit: Vec<(u8, Uint128)>,
+) -> StdResult<Vec<SubMsg>> {
+    let ust_snapshot = deps
         .querier
         .query_balance(&env.contract.address, "uusd")?
         .amount;
-    let mut usd_balance = uusd_balance;
-    let b_luna_balance_response: Cw20BalanceResponse = deps.querier.query_wasm_smart(
-        state.collateral_token.to_string(),
-        &ExternalQueryMsg::Balance {
-            address: env.contract.address.to_string(),
+    BID_RETRACTION.save(
+        deps.storage,
+        &BidRetraction {
+            ust_snapshot,
+            amount,
+            resubmit,
         },
     )?;
+    let count = retract_bids.len();
+    let mut msgs = vec![];
+    for (i, (bid_idx, amount)) in retract_bids.into_iter().enumerate() {
+        let msg = CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.anchor_liquidation_queue.to_string(),
+            funds: vec![],
+            msg: to_binary(&ExternalMsg::RetractBid { bid_idx, amount })?,
+        });
+        msgs.push(if i + 1 == count {
+            SubMsg::reply_on_success(msg, RETRACT_REPLY_ID)
+        } else {
+            SubMsg::new(msg)
+        });
+    }
+    Ok(msgs)
+}
+
+// Book retracted UST and submit bids of rebalance
+// Fails whole message if less UST than expected was received
+fn retract_reply(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let retraction = BID_RETRACTION.load(deps.storage)?;
+    let ust_balance = deps
+        .querier
+        .query_balance(&env.contract.address, "uusd")?
+        .amount;
+    let received = ust_balance.checked_sub(retraction.ust_snapshot)?;
+    if received < retraction.amount {
+        return Err(Insufficient {});
+    }
+    BID_RETRACTION.remove(deps.storage);
+    let mut state = STATE.load(deps.storage)?;
+    state.idle_ust += received;
+    let mut res = Response::new().add_attributes(vec![
+        attr("action", "retract_reply"),
+        attr("received", received),
+    ]);
+    for (premium_slot, amount) in retraction.resubmit {
+        state.idle_ust = state.idle_ust.checked_sub(amount)?;
+        res = res
+            .add_message(submit_bid_msg(&state, amount, premium_slot)?)
+            .add_attribute(format!("premium_slot_{}", premium_slot), amount);
+    }
+    STATE.save(deps.storage, &state)?;
+    Ok(res)
+}
 
-    let mut b_luna_balance = b_luna_balance_response.balance;
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
+fn activate_bid(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let bids_idx: Vec<Uint128> = query_bids(deps.as_ref(), &env, &state)?
+        .into_iter()
+        .filter(
+            |item| matches!(item.wait_end, Some(wait_end) if wait_end < env.block.time.seconds()),
+        )
+        .map(|item| item.idx)
+        .collect();
+    Ok(Response::new()
+        .add_attributes(vec![attr("action", "activate"), attr("from", info.sender)])
+        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.anchor_liquidation_queue.to_string(),
+            funds: vec![],
+            msg: to_binary(&ExternalMsg::ActivateBids {
                 collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
-        )?;
-        for item in &res.bids {
-            usd_balance += Uint128::try_from(item.amount)?;
-            b_luna_balance += Uint128::try_from(item.pending_liquidated_collateral)?;
+                bids_idx: Some(bids_idx),
+            })?,
+        })))
+}
+
+// Result of UST withdrawal calculation
+struct WithdrawUstPlan {
+    // UST value of share
+    withdraw_cap: Uint128,
+    // UST to send to user
+    ust_amount: Uint128,
+    // Withdraw fee in UST value
+    fee: Uint128,
+    // Fee kept from UST in vault and from bLuna for swap wallet
+    fee_ust: Uint128,
+    fee_b_luna: Uint128,
+    // UST to retract from bids
+    retract_amount: Uint128,
+    // Retract bid messages (bid index, partial amount)
+    retract_bids: Vec<(Uint128, Option<Uint256>)>,
+    // bLuna to send to swap wallet for UST not covered by vault and bids
+    swap_b_luna: Uint128,
+    // Locked bLuna to unlock for swap
+    unlocked_b_luna: Uint128,
+    // Claims to remove and partially unlocked claim
+    remove_claims: Vec<U32Key>,
+    update_claim: Option<(U32Key, TokenRecord)>,
+}
+
+fn plan_withdraw_ust(
+    deps: Deps,
+    env: &Env,
+    state: &State,
+    share: Uint128,
+) -> Result<WithdrawUstPlan, ContractError> {
+    if share.is_zero() || share > state.total_supply {
+        return Err(Invalidate {});
+    }
+    let valuation = query_valuation(deps, env, state)?;
+    valuation.check_price(env, state)?;
+    let uusd_balance = valuation.idle_ust;
+    let b_luna_balance = valuation.total_b_luna();
+    // Calculate exact amount from share and total cap
+    let withdraw_cap = convert_to_assets(share, valuation.total_cap()?, state.total_supply);
+    if withdraw_cap.is_zero() {
+        return Err(Insufficient {});
+    }
+    let mut plan = WithdrawUstPlan {
+        withdraw_cap,
+        ust_amount: withdraw_cap,
+        fee: fee_amount(withdraw_cap, state.withdraw_fee),
+        fee_ust: Uint128::zero(),
+        fee_b_luna: Uint128::zero(),
+        retract_amount: Uint128::zero(),
+        retract_bids: vec![],
+        swap_b_luna: Uint128::zero(),
+        unlocked_b_luna: Uint128::zero(),
+        remove_claims: vec![],
+        update_claim: None,
+    };
+    // Withdraw if UST in vault is enough
+    if uusd_balance >= withdraw_cap {
+        take_withdraw_fee(&mut plan, &valuation)?;
+        return Ok(plan);
+    }
+    // Retract bids for insufficient UST in vault
+    let mut usd_balance = withdraw_cap - uusd_balance;
+    for item in query_bids(deps, env, state)? {
+        if !item.amount.is_zero() {
+            if item.amount < usd_balance.into() {
+                plan.retract_bids.push((item.idx, None));
+                usd_balance -= Uint128::try_from(item.amount)?;
+            } else {
+                plan.retract_bids.push((item.idx, Some(usd_balance.into())));
+                usd_balance = Uint128::zero();
+                break;
+            }
         }
-        if res.bids.len() < 31 {
-            break;
+    }
+    plan.ust_amount = withdraw_cap - usd_balance;
+    plan.retract_amount = plan.ust_amount - uusd_balance;
+    if !usd_balance.is_zero() {
+        let mut b_luna_withdraw = b_luna_balance * share * usd_balance
+            / withdraw_cap
+            / (state.total_supply - share * (withdraw_cap - usd_balance) / withdraw_cap);
+        plan.swap_b_luna = b_luna_withdraw;
+        // unlock
+        if !b_luna_withdraw.is_zero() && valuation.free_b_luna < b_luna_withdraw {
+            b_luna_withdraw -= valuation.free_b_luna;
+            let keys = CLAIM_LIST.keys(deps.storage, None, None, Order::Ascending);
+            for key in keys {
+                let claim = CLAIM_LIST.load(deps.storage, U32Key::from(key.clone()))?;
+                if b_luna_withdraw >= claim.amount {
+                    b_luna_withdraw -= claim.amount;
+                    plan.unlocked_b_luna += claim.amount;
+                    plan.remove_claims.push(U32Key::from(key));
+                } else {
+                    plan.unlocked_b_luna += b_luna_withdraw;
+                    let mut new_claim = claim.clone();
+                    new_claim.amount -= b_luna_withdraw;
+                    b_luna_withdraw = Uint128::zero();
+                    plan.update_claim = Some((U32Key::from(key), new_claim));
+                }
+                if b_luna_withdraw.is_zero() {
+                    break;
+                }
+            }
         }
-        start_after = Some(res.bids.last().unwrap().idx);
     }
-    let price_response: PriceResponse = deps.querier.query_wasm_smart(
-        state.price_oracle.to_string(),
-        &ExternalQueryMsg::Price {
-            base: state.collateral_token.to_string(),
-            quote: "uusd".to_string(),
-        },
+    take_withdraw_fee(&mut plan, &valuation)?;
+    Ok(plan)
+}
+
+// Keep withdraw fee from UST sent to user first, then from bLuna sent to swap wallet
+fn take_withdraw_fee(plan: &mut WithdrawUstPlan, valuation: &VaultValuation) -> StdResult<()> {
+    plan.fee_ust = plan.fee.min(plan.ust_amount);
+    plan.ust_amount -= plan.fee_ust;
+    let fee_rest = plan.fee - plan.fee_ust;
+    if !fee_rest.is_zero() {
+        plan.fee_b_luna = valuation.to_b_luna(fee_rest)?.min(plan.swap_b_luna);
+        plan.swap_b_luna -= plan.fee_b_luna;
+    }
+    Ok(())
+}
+
+// Shares of address credited by third party deposits still in withdraw lock
+fn locked_share(deps: Deps, env: &Env, state: &State, address: &[u8]) -> StdResult<Uint128> {
+    match LOCKED_SHARES.may_load(deps.storage, address)? {
+        Some(record) if record.timestamp.plus_seconds(state.withdraw_lock) >= env.block.time => {
+            Ok(record.amount)
+        }
+        _ => Ok(Uint128::zero()),
+    }
+}
+
+// Check withdraw lock of sender and deduct share from sender balance
+// Full unlocked balance is withdrawn if share is not given
+fn take_withdraw_share(
+    deps: DepsMut,
+    env: &Env,
+    state: &State,
+    sender: &str,
+    share: Option<Uint128>,
+) -> Result<Uint128, ContractError> {
+    let sender_raw = deps.api.addr_canonicalize(sender)?;
+    let last_timestamp = LAST_DEPOSIT.may_load(deps.storage, sender_raw.as_slice())?;
+    if let Some(timestamp) = last_timestamp {
+        if timestamp.plus_seconds(state.withdraw_lock) >= env.block.time {
+            return Err(Locked {});
+        }
+    }
+    let balance = BALANCES
+        .may_load(deps.storage, sender_raw.as_slice())?
+        .unwrap_or_default();
+    if balance.is_zero() {
+        return Err(NoShares {});
+    }
+    let unlocked = balance.saturating_sub(locked_share(
+        deps.as_ref(),
+        env,
+        state,
+        sender_raw.as_slice(),
+    )?);
+    let share = share.unwrap_or(unlocked);
+    if share.is_zero() {
+        return Err(if unlocked.is_zero() {
+            Locked {}
+        } else {
+            Invalidate {}
+        });
+    }
+    if share > unlocked && share <= balance {
+        return Err(Locked {});
+    }
+    BALANCES.save(
+        deps.storage,
+        sender_raw.as_slice(),
+        &balance.checked_sub(share)?,
     )?;
-    let price = price_response.rate;
-    // Calculate total cap
-    let total_cap =
-        b_luna_balance + Uint128::try_from(Uint256::from(usd_balance).mul(price.inv().unwrap()))?;
-    // Calculate exact amount from share and total cap
-    let withdraw_cap = total_cap * share / state.total_supply;
+    Ok(share)
+}
 
+fn withdraw_ust(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    share: Option<Uint128>,
+    min_amount: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    // Withdrawn assets are sent to recipient, sender by default
+    let recipient = deps.api.addr_validate(
+        &recipient
+            .unwrap_or_else(|| msg_sender.clone())
+            .to_lowercase(),
+    )?;
+    let mut state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    let share = take_withdraw_share(deps.branch(), &env, &state, &msg_sender, share)?;
+    accrue_fees(deps.branch(), &env, &mut state)?;
+    let plan = plan_withdraw_ust(deps.as_ref(), &env, &state, share)?;
+    let withdraw_cap = plan.withdraw_cap;
+    check_slippage(min_amount, withdraw_cap - plan.fee)?;
+    let mut messages = vec![];
+    for (bid_idx, amount) in plan.retract_bids {
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.anchor_liquidation_queue.to_string(),
+            msg: to_binary(&ExternalMsg::RetractBid { bid_idx, amount })?,
+            funds: vec![],
+        }));
+    }
+    if !plan.ust_amount.is_zero() {
+        messages.push(CosmosMsg::Bank(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![Coin {
+                denom: "uusd".to_string(),
+                amount: plan.ust_amount,
+            }],
+        }));
+    }
+    // UST not covered by vault and bids is paid by swap wallet
+    if !plan.swap_b_luna.is_zero() {
+        // swap on wallet
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.collateral_token.to_string(),
+            msg: to_binary(&ExternalMsg::Send {
+                contract: state.swap_wallet.to_string(),
+                amount: plan.swap_b_luna,
+                msg: to_binary(&recipient)?,
+            })?,
+            funds: vec![],
+        }));
+    }
+    for key in plan.remove_claims {
+        CLAIM_LIST.remove(deps.storage, key);
+    }
+    if let Some((key, claim)) = plan.update_claim {
+        CLAIM_LIST.save(deps.storage, key, &claim)?;
+    }
+    if !plan.unlocked_b_luna.is_zero() {
+        state.locked_b_luna -= plan.unlocked_b_luna;
+    }
+    // Retracted UST comes to vault and is sent to user
+    state.idle_ust =
+        (state.idle_ust + plan.retract_amount).checked_sub(plan.ust_amount + plan.fee_ust)?;
+    // Free bLuna is sent to swap wallet before unlocked bLuna
+    state.free_b_luna = state
+        .free_b_luna
+        .saturating_sub(plan.swap_b_luna + plan.fee_b_luna);
+    state.insurance_reserve += plan.fee_ust;
+    state.insurance_reserve_b_luna += plan.fee_b_luna;
     state.total_supply -= share;
     STATE.save(deps.storage, &state)?;
+    let mut attrs = vec![
+        attr("action", "withdraw"),
+        attr("from", msg_sender.clone()),
+        attr("to", recipient.to_string()),
+        attr("share", share),
+        attr("amount", withdraw_cap - plan.fee),
+        attr("fee", plan.fee),
+    ];
+    if !plan.unlocked_b_luna.is_zero() {
+        attrs.push(attr("unlocked", plan.unlocked_b_luna.to_string()));
+    }
+    Ok(Response::new().add_messages(messages).add_attributes(attrs))
+}
+
+// Result of bLuna withdrawal calculation
+struct WithdrawBLunaPlan {
+    // bLuna value of share
+    withdraw_cap: Uint128,
+    // Withdraw fee in bLuna
+    fee: Uint128,
+    // Unlocked bLuna in vault
+    free_b_luna: Uint128,
+}
+
+fn plan_withdraw_b_luna(
+    deps: Deps,
+    env: &Env,
+    state: &State,
+    share: Uint128,
+) -> Result<WithdrawBLunaPlan, ContractError> {
+    if share.is_zero() || share > state.total_supply {
+        return Err(Invalidate {});
+    }
+    let valuation = query_valuation(deps, env, state)?;
+    valuation.check_price(env, state)?;
+    // Calculate exact amount from share and total cap
+    let withdraw_cap =
+        convert_to_assets(share, valuation.total_cap_in_b_luna()?, state.total_supply);
+    Ok(WithdrawBLunaPlan {
+        withdraw_cap,
+        fee: fee_amount(withdraw_cap, state.withdraw_fee),
+        free_b_luna: valuation.free_b_luna,
+    })
+}
+
+fn withdraw_b_luna(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    share: Option<Uint128>,
+    min_amount: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    // Withdrawn assets are sent to recipient, sender by default
+    let recipient = deps.api.addr_validate(
+        &recipient
+            .unwrap_or_else(|| msg_sender.clone())
+            .to_lowercase(),
+    )?;
+    let mut state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    let share = take_withdraw_share(deps.branch(), &env, &state, &msg_sender, share)?;
+    accrue_fees(deps.branch(), &env, &mut state)?;
+    let plan = plan_withdraw_b_luna(deps.as_ref(), &env, &state, share)?;
+    let withdraw_cap = plan.withdraw_cap;
+    check_slippage(min_amount, withdraw_cap - plan.fee)?;
+
     // Withdraw if bLuna in vault is enough
-    if b_luna_balance_response.balance - state.locked_b_luna >= withdraw_cap {
+    if plan.free_b_luna >= withdraw_cap {
+        state.total_supply -= share;
+        state.free_b_luna -= withdraw_cap;
+        state.insurance_reserve_b_luna += plan.fee;
+        STATE.save(deps.storage, &state)?;
         Ok(Response::new()
             .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
                 contract_addr: state.collateral_token.to_string(),
                 msg: to_binary(&ExternalMsg::Transfer {
-                    recipient: info.sender.to_string(),
-                    amount: withdraw_cap,
+                    recipient: recipient.to_string(),
+                    amount: withdraw_cap - plan.fee,
                 })?,
                 funds: vec![],
             }))
             .add_attributes(vec![
                 attr("action", "withdraw"),
-                attr("to", info.sender),
+                attr("from", msg_sender.clone()),
+                attr("to", recipient.to_string()),
                 attr("share", share),
-                attr("amount", withdraw_cap),
+                attr("amount", withdraw_cap - plan.fee),
+                attr("fee", plan.fee),
             ]))
     } else {
         Err(Locked {})
     }
 }
 
-fn claim_liquidation(
-    deps: DepsMut,
-    env: Env,
-    info: MessageInfo,
-) -> Result<Response, ContractError> {
-    let mut b_luna_balance = Uint128::zero();
-    let mut start_after = Some(Uint128::zero());
-    let mut state = STATE.load(deps.storage)?;
-    let mut bids_idx = vec![];
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
-        )?;
-        for item in &res.bids {
-            if !item.pending_liquidated_collateral.is_zero() {
-                b_luna_balance += Uint128::try_from(item.pending_liquidated_collateral)?;
-                bids_idx.push(item.idx);
-            }
-        }
-        if res.bids.len() < 31 {
-            break;
-        }
-        start_after = Some(res.bids.last().unwrap().idx);
+struct WithdrawProRataPlan {
+    // Slice of UST and unlocked bLuna in vault, before fee
+    ust_amount: Uint128,
+    b_luna_amount: Uint128,
+    ust_fee: Uint128,
+    b_luna_fee: Uint128,
+    // Shares of the paid slice, burnt
+    burn_share: Uint128,
+    // Shares of the slice kept in vault, escrowed in receipt
+    kept_share: Uint128,
+    // Slice of assets kept in vault
+    bid_ust: Uint128,
+    pending_collateral: Uint128,
+    locked_b_luna: Uint128,
+}
+
+// Split share by liquid and locked part of total cap
+// Both parts are valued with convert_to_assets, so paid assets match burnt shares
+fn plan_withdraw_pro_rata(
+    deps: Deps,
+    env: &Env,
+    state: &State,
+    share: Uint128,
+) -> Result<WithdrawProRataPlan, ContractError> {
+    if share.is_zero() || share > state.total_supply {
+        return Err(Invalidate {});
     }
-    if b_luna_balance.is_zero() {
+    let valuation = query_valuation(deps, env, state)?;
+    valuation.check_price(env, state)?;
+    let total_cap = valuation.total_cap()?;
+    let liquid_cap = valuation.idle_ust
+        + Uint128::try_from(Uint256::from(valuation.free_b_luna) * valuation.price)?;
+    if liquid_cap.is_zero() {
         return Err(Insufficient {});
     }
-    let last_key = CLAIM_LIST.keys(deps.storage, None, None, Ascending).last();
-    let new_key = if let Some(value) = last_key {
-        u32::from_be_bytes(value.as_slice().try_into().unwrap()) + 1
-    } else {
-        0
+    let locked_cap = total_cap - liquid_cap;
+    let burn_share = share.multiply_ratio(liquid_cap, total_cap);
+    let kept_share = share - burn_share;
+    let value = convert_to_assets(burn_share, total_cap, state.total_supply).min(liquid_cap);
+    let kept_value = convert_to_assets(kept_share, total_cap, state.total_supply).min(locked_cap);
+    let liquid_slice = |amount: Uint128| amount.multiply_ratio(value, liquid_cap);
+    let locked_slice = |amount: Uint128| {
+        if locked_cap.is_zero() {
+            Uint128::zero()
+        } else {
+            amount.multiply_ratio(kept_value, locked_cap)
+        }
     };
-    CLAIM_LIST.save(
-        deps.storage,
-        U32Key::from(new_key),
-        &TokenRecord {
-            amount: b_luna_balance,
-            timestamp: env.block.time,
-        },
-    )?;
+    let ust_amount = liquid_slice(valuation.idle_ust);
+    let b_luna_amount = liquid_slice(valuation.free_b_luna);
+    if ust_amount.is_zero() && b_luna_amount.is_zero() {
+        return Err(Insufficient {});
+    }
+    Ok(WithdrawProRataPlan {
+        ust_amount,
+        b_luna_amount,
+        ust_fee: fee_amount(ust_amount, state.withdraw_fee),
+        b_luna_fee: fee_amount(b_luna_amount, state.withdraw_fee),
+        burn_share,
+        kept_share,
+        bid_ust: locked_slice(valuation.bid_ust),
+        pending_collateral: locked_slice(valuation.pending_collateral),
+        locked_b_luna: locked_slice(valuation.locked_b_luna),
+    })
+}
 
-    state.locked_b_luna += b_luna_balance;
-    STATE.save(deps.storage, &state)?;
-    Ok(Response::new()
-        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: state.anchor_liquidation_queue.to_string(),
-            msg: to_binary(&ExternalMsg::ClaimLiquidations {
-                collateral_token: state.collateral_token.to_string(),
-                bids_idx: Some(bids_idx),
+// Pay liquid slice of shares and escrowed shares of owner, escrow the rest in receipt
+fn settle_pro_rata(
+    mut deps: DepsMut,
+    env: &Env,
+    state: &mut State,
+    owner: &str,
+    share: Uint128,
+    recipient: &Addr,
+) -> Result<(WithdrawProRataPlan, Vec<CosmosMsg>), ContractError> {
+    let owner_raw = deps.api.addr_canonicalize(owner)?;
+    let escrowed_share = WITHDRAW_RECEIPTS
+        .may_load(deps.storage, owner_raw.as_slice())?
+        .map(|receipt| receipt.share)
+        .unwrap_or_default();
+    accrue_fees(deps.branch(), env, state)?;
+    let plan = plan_withdraw_pro_rata(deps.as_ref(), env, state, escrowed_share + share)?;
+    if plan.kept_share.is_zero() {
+        WITHDRAW_RECEIPTS.remove(deps.storage, owner_raw.as_slice());
+    } else {
+        WITHDRAW_RECEIPTS.save(
+            deps.storage,
+            owner_raw.as_slice(),
+            &WithdrawReceipt {
+                share: plan.kept_share,
+                bid_ust: plan.bid_ust,
+                pending_collateral: plan.pending_collateral,
+                locked_b_luna: plan.locked_b_luna,
+                timestamp: env.block.time,
+            },
+        )?;
+    }
+    state.total_supply -= plan.burn_share;
+    state.idle_ust -= plan.ust_amount;
+    state.free_b_luna -= plan.b_luna_amount;
+    state.insurance_reserve += plan.ust_fee;
+    state.insurance_reserve_b_luna += plan.b_luna_fee;
+    STATE.save(deps.storage, state)?;
+
+    let mut messages: Vec<CosmosMsg> = vec![];
+    if plan.ust_amount > plan.ust_fee {
+        messages.push(CosmosMsg::Bank(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![Coin {
+                denom: "uusd".to_string(),
+                amount: plan.ust_amount - plan.ust_fee,
+            }],
+        }));
+    }
+    if plan.b_luna_amount > plan.b_luna_fee {
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.collateral_token.to_string(),
+            msg: to_binary(&ExternalMsg::Transfer {
+                recipient: recipient.to_string(),
+                amount: plan.b_luna_amount - plan.b_luna_fee,
             })?,
             funds: vec![],
-        }))
-        .add_attributes(vec![
-            attr("action", "liquidate"),
-            attr("from", &info.sender),
-            attr("amount", b_luna_balance.to_string()),
-        ]))
+        }));
+    }
+    Ok((plan, messages))
 }
 
-fn unlock(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
-    let keys = CLAIM_LIST.keys(deps.storage, None, None, Order::Ascending);
-    let mut remove_keys = Vec::new();
-    let mut unlocked_b_luna = Uint128::zero();
-    let state = STATE.load(deps.storage)?;
-    for key in keys {
-        let claim = CLAIM_LIST.load(deps.storage, U32Key::from(key.clone()))?;
+fn pro_rata_attributes(plan: &WithdrawProRataPlan) -> Vec<Attribute> {
+    vec![
+        attr("share", plan.burn_share),
+        attr("ust_amount", plan.ust_amount - plan.ust_fee),
+        attr("b_luna_amount", plan.b_luna_amount - plan.b_luna_fee),
+        attr("ust_fee", plan.ust_fee),
+        attr("b_luna_fee", plan.b_luna_fee),
+        attr("kept_share", plan.kept_share),
+    ]
+}
+
+fn withdraw_pro_rata(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    share: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    // Withdrawn assets are sent to recipient, sender by default
+    let recipient = deps.api.addr_validate(
+        &recipient
+            .unwrap_or_else(|| msg_sender.clone())
+            .to_lowercase(),
+    )?;
+    let mut state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    // Shares are settled together with shares escrowed on earlier withdrawals
+    let share = take_withdraw_share(deps.branch(), &env, &state, &msg_sender, share)?;
+    let (plan, messages) = settle_pro_rata(deps, &env, &mut state, &msg_sender, share, &recipient)?;
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attributes(vec![
+            attr("action", "withdraw_pro_rata"),
+            attr("from", msg_sender),
+            attr("to", recipient.to_string()),
+        ])
+        .add_attributes(pro_rata_attributes(&plan)))
+}
+
+fn claim_receipt(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    let recipient = deps.api.addr_validate(&msg_sender)?;
+    let mut state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    if !WITHDRAW_RECEIPTS.has(
+        deps.storage,
+        deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
+    ) {
+        return Err(NoShares {});
+    }
+    let (plan, messages) = settle_pro_rata(
+        deps,
+        &env,
+        &mut state,
+        &msg_sender,
+        Uint128::zero(),
+        &recipient,
+    )?;
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_attributes(vec![
+            attr("action", "claim_receipt"),
+            attr("from", msg_sender),
+        ])
+        .add_attributes(pro_rata_attributes(&plan)))
+}
+
+fn request_withdraw(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    share: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    let state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    // Escrowed shares stay in total supply until fulfilled
+    let share = take_withdraw_share(deps.branch(), &env, &state, &msg_sender, share)?;
+    let mut queue = WITHDRAW_QUEUE.may_load(deps.storage)?.unwrap_or_default();
+    let ticket_id = queue.next_id;
+    WITHDRAW_TICKETS.save(
+        deps.storage,
+        U32Key::from(ticket_id),
+        &WithdrawTicket {
+            owner: deps.api.addr_validate(&msg_sender)?,
+            share,
+            amount: Uint128::zero(),
+            fulfilled: false,
+            timestamp: env.block.time,
+        },
+    )?;
+    USER_TICKETS.save(
+        deps.storage,
+        (
+            deps.api.addr_canonicalize(&msg_sender)?.as_slice(),
+            U32Key::from(ticket_id),
+        ),
+        &true,
+    )?;
+    queue.next_id += 1;
+    queue.pending += 1;
+    queue.queued_share += share;
+    WITHDRAW_QUEUE.save(deps.storage, &queue)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "request_withdraw"),
+        attr("from", msg_sender),
+        attr("ticket_id", ticket_id.to_string()),
+        attr("share", share),
+    ]))
+}
+
+fn fulfill_withdraw(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    check_permission(deps.as_ref(), &info)?;
+    let mut state = STATE.load(deps.storage)?;
+    if state.epoch_mode {
+        return Err(EpochModeEnabled {});
+    }
+    accrue_fees(deps.branch(), &env, &mut state)?;
+    let mut total_cap = query_valuation(deps.as_ref(), &env, &state)?.total_cap()?;
+    let mut queue = WITHDRAW_QUEUE.may_load(deps.storage)?.unwrap_or_default();
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let tickets = pending_tickets(deps.as_ref(), queue.head)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut fulfilled = 0u32;
+    let mut share = Uint128::zero();
+    let mut amount = Uint128::zero();
+    let mut fee = Uint128::zero();
+    // Tickets are paid in order, tickets idle UST can not cover are skipped
+    for (key, mut ticket) in tickets {
+        let withdraw_cap = convert_to_assets(ticket.share, total_cap, state.total_supply);
+        if withdraw_cap > state.idle_ust {
+            continue;
+        }
+        let ticket_fee = fee_amount(withdraw_cap, state.withdraw_fee);
+        ticket.amount = withdraw_cap - ticket_fee;
+        ticket.fulfilled = true;
+        let ticket_id = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+        WITHDRAW_TICKETS.save(deps.storage, U32Key::from(ticket_id), &ticket)?;
+        state.idle_ust -= withdraw_cap;
+        state.insurance_reserve += ticket_fee;
+        state.total_supply -= ticket.share;
+        total_cap -= withdraw_cap;
+        queue.pending -= 1;
+        queue.queued_share -= ticket.share;
+        queue.claimable_ust += ticket.amount;
+        fulfilled += 1;
+        share += ticket.share;
+        amount += ticket.amount;
+        fee += ticket_fee;
+    }
+    if fulfilled == 0 {
+        return Err(Insufficient {});
+    }
+    queue.head = match pending_tickets(deps.as_ref(), queue.head).next() {
+        Some(ticket) => u32::from_be_bytes(ticket?.0.as_slice().try_into().unwrap()),
+        None => queue.next_id,
+    };
+    STATE.save(deps.storage, &state)?;
+    WITHDRAW_QUEUE.save(deps.storage, &queue)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "fulfill_withdraw"),
+        attr("tickets", fulfilled.to_string()),
+        attr("share", share),
+        attr("amount", amount),
+        attr("fee", fee),
+    ]))
+}
+
+// Tickets not fulfilled yet from id on, in order
+fn pending_tickets(
+    deps: Deps<'_>,
+    start: u32,
+) -> impl Iterator<Item = StdResult<(Vec<u8>, WithdrawTicket)>> + '_ {
+    WITHDRAW_TICKETS
+        .range(
+            deps.storage,
+            Some(Bound::inclusive_int(start)),
+            None,
+            Ascending,
+        )
+        .filter(|item| !matches!(item, Ok((_, ticket)) if ticket.fulfilled))
+}
+
+// Load withdraw ticket owned by sender
+fn load_ticket(
+    deps: Deps,
+    info: &MessageInfo,
+    ticket_id: u32,
+) -> Result<WithdrawTicket, ContractError> {
+    let ticket = WITHDRAW_TICKETS.load(deps.storage, U32Key::from(ticket_id))?;
+    if ticket.owner.to_string().to_lowercase() != info.sender.to_string().to_lowercase() {
+        return Err(Unauthorized {});
+    }
+    Ok(ticket)
+}
+
+fn remove_ticket(deps: DepsMut, ticket: &WithdrawTicket, ticket_id: u32) -> StdResult<()> {
+    WITHDRAW_TICKETS.remove(deps.storage, U32Key::from(ticket_id));
+    USER_TICKETS.remove(
+        deps.storage,
+        (
+            deps.api
+                .addr_canonicalize(&ticket.owner.to_string().to_lowercase())?
+                .as_slice(),
+            U32Key::from(ticket_id),
+        ),
+    );
+    Ok(())
+}
+
+fn claim_withdraw(
+    mut deps: DepsMut,
+    info: MessageInfo,
+    ticket_id: u32,
+) -> Result<Response, ContractError> {
+    let ticket = load_ticket(deps.as_ref(), &info, ticket_id)?;
+    if !ticket.fulfilled {
+        return Err(WithdrawPending {});
+    }
+    remove_ticket(deps.branch(), &ticket, ticket_id)?;
+    let mut queue = WITHDRAW_QUEUE.load(deps.storage)?;
+    queue.claimable_ust -= ticket.amount;
+    WITHDRAW_QUEUE.save(deps.storage, &queue)?;
+    let mut messages: Vec<CosmosMsg> = vec![];
+    if !ticket.amount.is_zero() {
+        messages.push(CosmosMsg::Bank(BankMsg::Send {
+            to_address: ticket.owner.to_string(),
+            amount: vec![Coin {
+                denom: "uusd".to_string(),
+                amount: ticket.amount,
+            }],
+        }));
+    }
+    Ok(Response::new().add_messages(messages).add_attributes(vec![
+        attr("action", "claim_withdraw"),
+        attr("to", ticket.owner),
+        attr("ticket_id", ticket_id.to_string()),
+        attr("amount", ticket.amount),
+    ]))
+}
+
+fn cancel_withdraw(
+    mut deps: DepsMut,
+    info: MessageInfo,
+    ticket_id: u32,
+) -> Result<Response, ContractError> {
+    let ticket = load_ticket(deps.as_ref(), &info, ticket_id)?;
+    if ticket.fulfilled {
+        return Err(WithdrawFulfilled {});
+    }
+    remove_ticket(deps.branch(), &ticket, ticket_id)?;
+    // Escrowed shares go back to owner
+    BALANCES.update(
+        deps.storage,
+        deps.api
+            .addr_canonicalize(&ticket.owner.to_string().to_lowercase())?
+            .as_slice(),
+        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default() + ticket.share) },
+    )?;
+    let mut queue = WITHDRAW_QUEUE.load(deps.storage)?;
+    queue.pending -= 1;
+    queue.queued_share -= ticket.share;
+    WITHDRAW_QUEUE.save(deps.storage, &queue)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "cancel_withdraw"),
+        attr("to", ticket.owner),
+        attr("ticket_id", ticket_id.to_string()),
+        attr("share", ticket.share),
+    ]))
+}
+
+// Fail unless sender has submit_bid permission
+fn check_permission(deps: Deps, info: &MessageInfo) -> Result<(), ContractError> {
+    let permission = PERMISSIONS
+        .may_load(
+            deps.storage,
+            deps.api
+                .addr_canonicalize(info.sender.to_string().to_lowercase().as_str())?
+                .as_slice(),
+        )?
+        .unwrap_or(Permission { submit_bid: false });
+    if !permission.submit_bid {
+        return Err(Unauthorized {});
+    }
+    Ok(())
+}
+
+// Current epoch, empty if nothing was requested yet
+fn load_current_epoch(deps: Deps, state: &State) -> StdResult<Epoch> {
+    Ok(EPOCHS
+        .may_load(deps.storage, U32Key::from(state.current_epoch))?
+        .unwrap_or(Epoch {
+            start: state.epoch_start,
+            ..Epoch::default()
+        }))
+}
+
+fn request_deposit(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    // Only one coin
+    if info.funds.len() != 1 {
+        return Err(Invalidate {});
+    }
+    let amount: Uint128 = info.funds[0].amount;
+    // Only UST and non-zero amount
+    if info.funds[0].denom != "uusd" || amount.is_zero() {
+        return Err(Invalidate {});
+    }
+    let mut state = STATE.load(deps.storage)?;
+    if !state.epoch_mode {
+        return Err(EpochModeDisabled {});
+    }
+    if state.paused {
+        return Err(Paused {});
+    }
+    let msg_sender = info.sender.to_string().to_lowercase();
+    let sender_raw = deps.api.addr_canonicalize(&msg_sender)?;
+    if !is_allowed(deps.as_ref(), &state, sender_raw.as_slice())? {
+        return Err(Unauthorized {});
+    }
+    let key = (sender_raw.as_slice(), U32Key::from(state.current_epoch));
+    let mut epoch = load_current_epoch(deps.as_ref(), &state)?;
+    let mut request = EPOCH_REQUESTS
+        .may_load(deps.storage, key.clone())?
+        .unwrap_or_default();
+    // Caps count UST already requested in epoch
+    let total_cap = query_valuation(deps.as_ref(), &env, &state)?.total_cap()?;
+    let balance = BALANCES
+        .may_load(deps.storage, sender_raw.as_slice())?
+        .unwrap_or_default();
+    let (total_remaining, user_remaining) = remaining_capacity(&state, total_cap, balance);
+    let remaining = total_remaining
+        .saturating_sub(epoch.deposit_ust)
+        .min(user_remaining.saturating_sub(request.deposit_ust));
+    if amount - fee_amount(amount, state.deposit_fee) > remaining {
+        return Err(DepositCapReached { remaining });
+    }
+    LAST_DEPOSIT.save(deps.storage, sender_raw.as_slice(), &env.block.time)?;
+    epoch.deposit_ust += amount;
+    request.deposit_ust += amount;
+    state.epoch_ust += amount;
+    EPOCHS.save(deps.storage, U32Key::from(state.current_epoch), &epoch)?;
+    EPOCH_REQUESTS.save(deps.storage, key, &request)?;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "request_deposit"),
+        attr("from", msg_sender),
+        attr("epoch", state.current_epoch.to_string()),
+        attr("amount", amount),
+    ]))
+}
+
+fn request_redeem(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    share: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    let state = STATE.load(deps.storage)?;
+    if !state.epoch_mode {
+        return Err(EpochModeDisabled {});
+    }
+    // Escrowed shares stay in total supply until epoch is closed
+    let share = take_withdraw_share(deps.branch(), &env, &state, &msg_sender, share)?;
+    let sender_raw = deps.api.addr_canonicalize(&msg_sender)?;
+    let key = (sender_raw.as_slice(), U32Key::from(state.current_epoch));
+    let mut epoch = load_current_epoch(deps.as_ref(), &state)?;
+    let mut request = EPOCH_REQUESTS
+        .may_load(deps.storage, key.clone())?
+        .unwrap_or_default();
+    epoch.redeem_share += share;
+    request.redeem_share += share;
+    EPOCHS.save(deps.storage, U32Key::from(state.current_epoch), &epoch)?;
+    EPOCH_REQUESTS.save(deps.storage, key, &request)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "request_redeem"),
+        attr("from", msg_sender),
+        attr("epoch", state.current_epoch.to_string()),
+        attr("share", share),
+    ]))
+}
+
+fn close_epoch(mut deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    check_permission(deps.as_ref(), &info)?;
+    let mut state = STATE.load(deps.storage)?;
+    if state.epoch_start + state.epoch_length > env.block.time.seconds() {
+        return Err(EpochNotEnded {});
+    }
+    accrue_fees(deps.branch(), &env, &mut state)?;
+    let mut epoch = load_current_epoch(deps.as_ref(), &state)?;
+    // Deposits and redemptions are priced at the same snapshot
+    let total_cap = query_valuation(deps.as_ref(), &env, &state)?.total_cap()?;
+    let total_supply = state.total_supply;
+    if !epoch.deposit_ust.is_zero() {
+        // Minted shares are credited to depositors on claim
+        let plan = plan_deposit(deps.as_ref(), &env, &state, epoch.deposit_ust)?;
+        mint_plan_shares(deps.branch(), &env, &mut state, &plan)?;
+        state.idle_ust += epoch.deposit_ust - plan.fee;
+        state.insurance_reserve += plan.fee;
+        epoch.minted_share = plan.share;
+    }
+    // Redemptions are paid from idle UST including epoch deposits
+    // Shares idle UST can not cover are rolled into next epoch
+    let mut withdraw_cap = convert_to_assets(epoch.redeem_share, total_cap, total_supply);
+    let mut paid_share = epoch.redeem_share;
+    if withdraw_cap > state.idle_ust {
+        paid_share = epoch
+            .redeem_share
+            .multiply_ratio(state.idle_ust, withdraw_cap);
+        withdraw_cap = convert_to_assets(paid_share, total_cap, total_supply).min(state.idle_ust);
+    }
+    let withdraw_fee = fee_amount(withdraw_cap, state.withdraw_fee);
+    state.idle_ust -= withdraw_cap;
+    state.total_supply -= paid_share;
+    state.insurance_reserve += withdraw_fee;
+    epoch.redeemed_ust = withdraw_cap - withdraw_fee;
+    epoch.rolled_share = epoch.redeem_share - paid_share;
+    epoch.closed = true;
+    state.epoch_ust = state.epoch_ust - epoch.deposit_ust + epoch.redeemed_ust;
+    EPOCHS.save(deps.storage, U32Key::from(state.current_epoch), &epoch)?;
+    let closed_epoch = state.current_epoch;
+    state.current_epoch += 1;
+    state.epoch_start = env.block.time.seconds();
+    if !epoch.rolled_share.is_zero() {
+        let next_epoch = Epoch {
+            start: state.epoch_start,
+            redeem_share: epoch.rolled_share,
+            ..Epoch::default()
+        };
+        EPOCHS.save(deps.storage, U32Key::from(state.current_epoch), &next_epoch)?;
+    }
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "close_epoch"),
+        attr("epoch", closed_epoch.to_string()),
+        attr("deposit_ust", epoch.deposit_ust),
+        attr("minted_share", epoch.minted_share),
+        attr("redeem_share", epoch.redeem_share),
+        attr("redeemed_ust", epoch.redeemed_ust),
+        attr("rolled_share", epoch.rolled_share),
+    ]))
+}
+
+// Shares, UST and rolled redeem shares of request in closed epoch
+fn epoch_claim(epoch: &Epoch, request: &EpochRequest) -> (Uint128, Uint128, Uint128) {
+    if !epoch.closed {
+        return (Uint128::zero(), Uint128::zero(), Uint128::zero());
+    }
+    let share = if epoch.deposit_ust.is_zero() {
+        Uint128::zero()
+    } else {
+        epoch
+            .minted_share
+            .multiply_ratio(request.deposit_ust, epoch.deposit_ust)
+    };
+    let (ust, rolled_share) = if epoch.redeem_share.is_zero() {
+        (Uint128::zero(), Uint128::zero())
+    } else {
+        (
+            epoch
+                .redeemed_ust
+                .multiply_ratio(request.redeem_share, epoch.redeem_share),
+            epoch
+                .rolled_share
+                .multiply_ratio(request.redeem_share, epoch.redeem_share),
+        )
+    };
+    (share, ust, rolled_share)
+}
+
+fn claim_epoch(deps: DepsMut, info: MessageInfo, epoch_id: u32) -> Result<Response, ContractError> {
+    let msg_sender = info.sender.to_string().to_lowercase();
+    let sender_raw = deps.api.addr_canonicalize(&msg_sender)?;
+    let epoch = EPOCHS.load(deps.storage, U32Key::from(epoch_id))?;
+    if !epoch.closed {
+        return Err(EpochOpen {});
+    }
+    let key = (sender_raw.as_slice(), U32Key::from(epoch_id));
+    let request = EPOCH_REQUESTS.load(deps.storage, key.clone())?;
+    EPOCH_REQUESTS.remove(deps.storage, key);
+    let (share, ust, rolled_share) = epoch_claim(&epoch, &request);
+    BALANCES.update(
+        deps.storage,
+        sender_raw.as_slice(),
+        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default() + share) },
+    )?;
+    // Unpaid redeem shares stay escrowed and are claimed from next epoch
+    if !rolled_share.is_zero() {
+        EPOCH_REQUESTS.update(
+            deps.storage,
+            (sender_raw.as_slice(), U32Key::from(epoch_id + 1)),
+            |request| -> StdResult<_> {
+                let mut request = request.unwrap_or_default();
+                request.redeem_share += rolled_share;
+                Ok(request)
+            },
+        )?;
+    }
+    let mut state = STATE.load(deps.storage)?;
+    state.epoch_ust -= ust;
+    STATE.save(deps.storage, &state)?;
+    let mut messages: Vec<CosmosMsg> = vec![];
+    if !ust.is_zero() {
+        messages.push(CosmosMsg::Bank(BankMsg::Send {
+            to_address: msg_sender.clone(),
+            amount: vec![Coin {
+                denom: "uusd".to_string(),
+                amount: ust,
+            }],
+        }));
+    }
+    Ok(Response::new().add_messages(messages).add_attributes(vec![
+        attr("action", "claim_epoch"),
+        attr("to", msg_sender),
+        attr("epoch", epoch_id.to_string()),
+        attr("share", share),
+        attr("amount", ust),
+        attr("rolled_share", rolled_share),
+    ]))
+}
+
+fn claim_liquidation(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let mut b_luna_balance = Uint128::zero();
+    let mut state = STATE.load(deps.storage)?;
+    let mut bids_idx = vec![];
+    for item in query_bids(deps.as_ref(), &env, &state)? {
+        if !item.pending_liquidated_collateral.is_zero() {
+            b_luna_balance += Uint128::try_from(item.pending_liquidated_collateral)?;
+            bids_idx.push(item.idx);
+        }
+    }
+    if b_luna_balance.is_zero() {
+        return Err(Insufficient {});
+    }
+    let last_key = CLAIM_LIST.keys(deps.storage, None, None, Ascending).last();
+    let new_key = if let Some(value) = last_key {
+        u32::from_be_bytes(value.as_slice().try_into().unwrap()) + 1
+    } else {
+        0
+    };
+    CLAIM_LIST.save(
+        deps.storage,
+        U32Key::from(new_key),
+        &TokenRecord {
+            amount: b_luna_balance,
+            timestamp: env.block.time,
+        },
+    )?;
+
+    state.locked_b_luna += b_luna_balance;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new()
+        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.anchor_liquidation_queue.to_string(),
+            msg: to_binary(&ExternalMsg::ClaimLiquidations {
+                collateral_token: state.collateral_token.to_string(),
+                bids_idx: Some(bids_idx),
+            })?,
+            funds: vec![],
+        }))
+        .add_attributes(vec![
+            attr("action", "liquidate"),
+            attr("from", &info.sender),
+            attr("amount", b_luna_balance.to_string()),
+        ]))
+}
+
+fn unlock(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let keys = CLAIM_LIST.keys(deps.storage, None, None, Order::Ascending);
+    let mut remove_keys = Vec::new();
+    let mut unlocked_b_luna = Uint128::zero();
+    let state = STATE.load(deps.storage)?;
+    for key in keys {
+        let claim = CLAIM_LIST.load(deps.storage, U32Key::from(key.clone()))?;
         if claim.timestamp.plus_seconds(state.lock_period) <= env.block.time {
             unlocked_b_luna += claim.amount;
             remove_keys.push(U32Key::from(key));
@@ -695,6 +2220,7 @@ fn unlock(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, Contra
     }
     let mut state = STATE.load(deps.storage)?;
     state.locked_b_luna -= unlocked_b_luna;
+    state.free_b_luna += unlocked_b_luna;
     STATE.save(deps.storage, &state)?;
     Ok(Response::new().add_attributes(vec![
         attr("action", "unlock"),
@@ -749,17 +2275,19 @@ fn set_permission(
 }
 
 fn swap(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
-    let state = STATE.load(deps.storage)?;
-    let b_luna_balance_response: Cw20BalanceResponse = deps.querier.query_wasm_smart(
-        state.collateral_token.to_string(),
-        &ExternalQueryMsg::Balance {
-            address: env.contract.address.to_string(),
-        },
-    )?;
-    let swap_amount = b_luna_balance_response.balance - state.locked_b_luna;
+    let mut state = STATE.load(deps.storage)?;
+    let swap_amount = state.free_b_luna;
     if swap_amount.is_zero() {
         return Err(Insufficient {});
     }
+    // Received UST is booked in reply
+    let ust_balance = deps
+        .querier
+        .query_balance(&env.contract.address, "uusd")?
+        .amount;
+    UST_SNAPSHOT.save(deps.storage, &ust_balance)?;
+    state.free_b_luna = Uint128::zero();
+    STATE.save(deps.storage, &state)?;
     let msg = ExternalMsg::Send {
         contract: state.astroport_router.to_string(),
         amount: swap_amount,
@@ -784,11 +2312,14 @@ fn swap(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, Contract
         })?,
     };
     Ok(Response::new()
-        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: state.collateral_token.to_string(),
-            msg: to_binary(&msg)?,
-            funds: vec![],
-        }))
+        .add_submessage(SubMsg::reply_on_success(
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: state.collateral_token.to_string(),
+                msg: to_binary(&msg)?,
+                funds: vec![],
+            }),
+            SWAP_REPLY_ID,
+        ))
         .add_attributes(vec![
             attr("action", "swap"),
             attr("from", info.sender),
@@ -796,6 +2327,25 @@ fn swap(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, Contract
         ]))
 }
 
+// Book UST received from swap
+fn swap_reply(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let ust_snapshot = UST_SNAPSHOT.load(deps.storage)?;
+    UST_SNAPSHOT.remove(deps.storage);
+    let ust_balance = deps
+        .querier
+        .query_balance(&env.contract.address, "uusd")?
+        .amount;
+    let received = ust_balance.checked_sub(ust_snapshot)?;
+    let mut state = STATE.load(deps.storage)?;
+    state.idle_ust += received;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "swap_reply"),
+        attr("received", received),
+    ]))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn update_config(
     deps: DepsMut,
     info: MessageInfo,
@@ -804,6 +2354,28 @@ fn update_config(
     swap_wallet: Option<Addr>,
     lock_period: Option<u64>,
     withdraw_lock: Option<u64>,
+    max_price_age: Option<u64>,
+    min_price: Option<Decimal256>,
+    max_price: Option<Decimal256>,
+    price_sources: Option<Vec<PriceSource>>,
+    price_aggregation: Option<PriceAggregation>,
+    performance_fee: Option<u64>,
+    fee_recipient: Option<Addr>,
+    management_fee: Option<u64>,
+    deposit_fee: Option<u64>,
+    withdraw_fee: Option<u64>,
+    max_total_cap: Option<Uint128>,
+    max_user_deposit: Option<Uint128>,
+    deposit_mode: Option<DepositMode>,
+    epoch_mode: Option<bool>,
+    epoch_length: Option<u64>,
+    bid_reserve: Option<Uint128>,
+    bid_targets: Option<Vec<BidTarget>>,
+    strategy: Option<String>,
+    clear_strategy: Option<bool>,
+    strategy_slots: Option<Vec<u8>>,
+    max_slot_amount: Option<Uint128>,
+    min_rebalance_delta: Option<Uint128>,
 ) -> Result<Response, ContractError> {
     let mut state = STATE.load(deps.storage)?;
     if state.owner.to_string().to_lowercase() != info.sender.to_string().to_lowercase() {
@@ -857,222 +2429,3488 @@ fn update_config(
             attributes.push(attr("withdraw_lock", withdraw_lock.to_string()));
         }
     }
-    if attributes.len() <= 2 {
-        return Err(Invalidate {});
+    if let Some(max_price_age) = max_price_age {
+        if max_price_age != state.max_price_age {
+            state.max_price_age = max_price_age;
+            attributes.push(attr("max_price_age", max_price_age.to_string()));
+        }
+    }
+    if let Some(min_price) = min_price {
+        if min_price != state.min_price {
+            state.min_price = min_price;
+            attributes.push(attr("min_price", min_price.to_string()));
+        }
+    }
+    if let Some(max_price) = max_price {
+        if max_price != state.max_price {
+            state.max_price = max_price;
+            attributes.push(attr("max_price", max_price.to_string()));
+        }
+    }
+    if let Some(price_sources) = price_sources {
+        if price_sources != state.price_sources {
+            state.price_sources = validate_price_sources(deps.as_ref(), price_sources)?;
+            attributes.push(attr("price_sources", state.price_sources.len().to_string()));
+        }
+    }
+    if let Some(price_aggregation) = price_aggregation {
+        if price_aggregation != state.price_aggregation {
+            attributes.push(attr(
+                "price_aggregation",
+                format!("{:?}", price_aggregation).to_lowercase(),
+            ));
+            state.price_aggregation = price_aggregation;
+        }
+    }
+    // Fee already earned is charged at old fee with HarvestFees
+    if let Some(performance_fee) = performance_fee {
+        if performance_fee != state.performance_fee {
+            if performance_fee > MAX_PERFORMANCE_FEE {
+                return Err(Invalidate {});
+            }
+            state.performance_fee = performance_fee;
+            attributes.push(attr("performance_fee", performance_fee.to_string()));
+        }
+    }
+    // Management fee is already settled up to this block with old fee
+    if let Some(management_fee) = management_fee {
+        if management_fee != state.management_fee {
+            if management_fee > MAX_MANAGEMENT_FEE {
+                return Err(Invalidate {});
+            }
+            state.management_fee = management_fee;
+            attributes.push(attr("management_fee", management_fee.to_string()));
+        }
+    }
+    if let Some(deposit_fee) = deposit_fee {
+        if deposit_fee != state.deposit_fee {
+            if deposit_fee > MAX_TRANSACTION_FEE {
+                return Err(Invalidate {});
+            }
+            state.deposit_fee = deposit_fee;
+            attributes.push(attr("deposit_fee", deposit_fee.to_string()));
+        }
+    }
+    if let Some(withdraw_fee) = withdraw_fee {
+        if withdraw_fee != state.withdraw_fee {
+            if withdraw_fee > MAX_TRANSACTION_FEE {
+                return Err(Invalidate {});
+            }
+            state.withdraw_fee = withdraw_fee;
+            attributes.push(attr("withdraw_fee", withdraw_fee.to_string()));
+        }
+    }
+    if let Some(max_total_cap) = max_total_cap {
+        if max_total_cap != state.max_total_cap {
+            state.max_total_cap = max_total_cap;
+            attributes.push(attr("max_total_cap", max_total_cap));
+        }
+    }
+    if let Some(max_user_deposit) = max_user_deposit {
+        if max_user_deposit != state.max_user_deposit {
+            state.max_user_deposit = max_user_deposit;
+            attributes.push(attr("max_user_deposit", max_user_deposit));
+        }
+    }
+    if let Some(deposit_mode) = deposit_mode {
+        if deposit_mode != state.deposit_mode {
+            attributes.push(attr(
+                "deposit_mode",
+                format!("{:?}", deposit_mode).to_lowercase(),
+            ));
+            state.deposit_mode = deposit_mode;
+        }
+    }
+    if let Some(epoch_mode) = epoch_mode {
+        if epoch_mode != state.epoch_mode {
+            // Pending tickets can not be fulfilled in epoch mode
+            // Escrowed deposits and redeem shares are settled only by closing epoch
+            if !epoch_mode {
+                let epoch = load_current_epoch(deps.as_ref(), &state)?;
+                if !epoch.deposit_ust.is_zero() || !epoch.redeem_share.is_zero() {
+                    return Err(EpochOpen {});
+                }
+            }
+            if epoch_mode
+                && WITHDRAW_QUEUE
+                    .may_load(deps.storage)?
+                    .unwrap_or_default()
+                    .pending
+                    > 0
+            {
+                return Err(WithdrawPending {});
+            }
+            state.epoch_mode = epoch_mode;
+            attributes.push(attr("epoch_mode", epoch_mode.to_string()));
+        }
+    }
+    if let Some(epoch_length) = epoch_length {
+        if epoch_length != state.epoch_length {
+            state.epoch_length = epoch_length;
+            attributes.push(attr("epoch_length", epoch_length.to_string()));
+        }
+    }
+    if let Some(bid_reserve) = bid_reserve {
+        if bid_reserve != state.bid_reserve {
+            state.bid_reserve = bid_reserve;
+            attributes.push(attr("bid_reserve", bid_reserve));
+        }
+    }
+    if let Some(bid_targets) = bid_targets {
+        if bid_targets != state.bid_targets {
+            state.bid_targets = validate_bid_targets(bid_targets)?;
+            attributes.push(attr("bid_targets", state.bid_targets.len().to_string()));
+        }
+    }
+    if clear_strategy == Some(true) {
+        if strategy.is_some() {
+            return Err(Invalidate {});
+        }
+        if state.strategy.is_some() {
+            state.strategy = None;
+            attributes.push(attr("strategy", ""));
+        }
+    } else if let Some(strategy) = strategy {
+        let strategy = deps.api.addr_validate(&strategy.to_lowercase())?;
+        if state.strategy.as_ref() != Some(&strategy) {
+            attributes.push(attr("strategy", strategy.to_string()));
+            state.strategy = Some(strategy);
+        }
+    }
+    if let Some(strategy_slots) = strategy_slots {
+        if strategy_slots != state.strategy_slots {
+            state.strategy_slots = validate_premium_slots(strategy_slots)?;
+            attributes.push(attr(
+                "strategy_slots",
+                state.strategy_slots.len().to_string(),
+            ));
+        }
+    }
+    if let Some(max_slot_amount) = max_slot_amount {
+        if max_slot_amount != state.max_slot_amount {
+            state.max_slot_amount = max_slot_amount;
+            attributes.push(attr("max_slot_amount", max_slot_amount));
+        }
+    }
+    if let Some(min_rebalance_delta) = min_rebalance_delta {
+        if min_rebalance_delta != state.min_rebalance_delta {
+            state.min_rebalance_delta = min_rebalance_delta;
+            attributes.push(attr("min_rebalance_delta", min_rebalance_delta));
+        }
+    }
+    if let Some(fee_recipient) = fee_recipient {
+        if fee_recipient.to_string().to_lowercase() != state.fee_recipient {
+            state.fee_recipient = deps
+                .api
+                .addr_validate(fee_recipient.to_string().to_lowercase().as_str())?;
+            attributes.push(attr("fee_recipient", state.fee_recipient.to_string()));
+        }
+    }
+    if state.min_price > state.max_price {
+        return Err(Invalidate {});
+    }
+    if attributes.len() <= 2 {
+        return Err(Invalidate {});
     }
     STATE.save(deps.storage, &state)?;
     Ok(Response::new().add_attributes(attributes))
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetInfo {} => to_binary(&query_info(deps)?),
-        QueryMsg::Config {} => to_binary(&query_config(deps)?),
-        // Get share from address
-        QueryMsg::Balance { address } => to_binary(&query_balance(deps, address)?),
-        // Get total cap in vault and anchor
-        QueryMsg::TotalCap {} => to_binary(&query_total_cap(deps, env)?),
-        // Return true if activate is needed
-        QueryMsg::Activatable {} => to_binary(&query_activatable(deps, env)?),
-        // Return true if liquidate is needed
-        QueryMsg::Claimable {} => to_binary(&query_claimable(deps, env)?),
-        QueryMsg::Permission { address } => to_binary(&query_permission(deps, address)?),
-        QueryMsg::Unlockable {} => to_binary(&query_unlockable(deps, env)?),
-        QueryMsg::LastDepositTimestamp { address } => {
-            to_binary(&query_last_deposit_timestamp(deps, address)?)
-        }
+fn cover_loss(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage)?;
+    if state.owner.to_string().to_lowercase() != info.sender.to_string().to_lowercase() {
+        return Err(Unauthorized {});
+    }
+    let ust_amount = state.insurance_reserve;
+    let b_luna_amount = state.insurance_reserve_b_luna;
+    if ust_amount.is_zero() && b_luna_amount.is_zero() {
+        return Err(Insufficient {});
     }
+    state.idle_ust += ust_amount;
+    state.free_b_luna += b_luna_amount;
+    state.insurance_reserve = Uint128::zero();
+    state.insurance_reserve_b_luna = Uint128::zero();
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "cover_loss"),
+        attr("from", info.sender),
+        attr("ust_amount", ust_amount),
+        attr("b_luna_amount", b_luna_amount),
+    ]))
 }
 
-fn query_info(deps: Deps) -> StdResult<InfoResponse> {
-    let state = STATE.load(deps.storage)?;
-    Ok(InfoResponse {
-        total_supply: state.total_supply,
-        locked_b_luna: state.locked_b_luna,
-    })
+// UST and bLuna balances of vault over booked assets, reserves and unclaimed withdrawals
+fn surplus(deps: Deps, env: &Env, state: &State) -> StdResult<(Uint128, Uint128)> {
+    let queue = WITHDRAW_QUEUE.may_load(deps.storage)?.unwrap_or_default();
+    let ust_balance = deps
+        .querier
+        .query_balance(&env.contract.address, "uusd")?
+        .amount;
+    let b_luna_balance = query_b_luna_balance(deps, env, state)?;
+    Ok((
+        ust_balance.saturating_sub(
+            state.idle_ust + state.insurance_reserve + state.epoch_ust + queue.claimable_ust,
+        ),
+        b_luna_balance.saturating_sub(
+            state.free_b_luna + state.locked_b_luna + state.insurance_reserve_b_luna,
+        ),
+    ))
 }
 
-fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
-    let state = STATE.load(deps.storage)?;
-    Ok(ConfigResponse {
-        owner: state.owner.to_string(),
-        paused: state.paused,
-        swap_wallet: state.swap_wallet.to_string(),
-        anchor_liquidation_queue: state.anchor_liquidation_queue.to_string(),
-        collateral_token: state.collateral_token.to_string(),
-        price_oracle: state.price_oracle.to_string(),
-        astroport_router: state.astroport_router.to_string(),
-        lock_period: state.lock_period,
-        withdraw_lock: state.withdraw_lock,
-    })
+fn sync(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    check_permission(deps.as_ref(), &info)?;
+    let mut state = STATE.load(deps.storage)?;
+    let (ust_amount, b_luna_amount) = surplus(deps.as_ref(), &env, &state)?;
+    if ust_amount.is_zero() && b_luna_amount.is_zero() {
+        return Err(Insufficient {});
+    }
+    state.idle_ust += ust_amount;
+    state.free_b_luna += b_luna_amount;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "sync"),
+        attr("from", info.sender),
+        attr("ust_amount", ust_amount),
+        attr("b_luna_amount", b_luna_amount),
+    ]))
 }
 
-fn query_balance(deps: Deps, address: String) -> StdResult<BalanceResponse> {
-    let address = deps.api.addr_canonicalize(&address)?;
-    let balance = BALANCES
-        .may_load(deps.storage, address.as_slice())?
-        .unwrap_or_default();
-    Ok(BalanceResponse { balance })
+fn skim(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    recipient: String,
+) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    if state.owner.to_string().to_lowercase() != info.sender.to_string().to_lowercase() {
+        return Err(Unauthorized {});
+    }
+    let recipient = deps.api.addr_validate(&recipient.to_lowercase())?;
+    let (ust_amount, b_luna_amount) = surplus(deps.as_ref(), &env, &state)?;
+    if ust_amount.is_zero() && b_luna_amount.is_zero() {
+        return Err(Insufficient {});
+    }
+    let mut messages: Vec<CosmosMsg> = vec![];
+    if !ust_amount.is_zero() {
+        messages.push(CosmosMsg::Bank(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![Coin {
+                denom: "uusd".to_string(),
+                amount: ust_amount,
+            }],
+        }));
+    }
+    if !b_luna_amount.is_zero() {
+        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.collateral_token.to_string(),
+            msg: to_binary(&ExternalMsg::Transfer {
+                recipient: recipient.to_string(),
+                amount: b_luna_amount,
+            })?,
+            funds: vec![],
+        }));
+    }
+    Ok(Response::new().add_messages(messages).add_attributes(vec![
+        attr("action", "skim"),
+        attr("from", info.sender),
+        attr("to", recipient),
+        attr("ust_amount", ust_amount),
+        attr("b_luna_amount", b_luna_amount),
+    ]))
 }
 
-fn query_total_cap(deps: Deps, env: Env) -> StdResult<TotalCapResponse> {
-    let mut usd_balance = deps
-        .querier
-        .query_balance(&env.contract.address, "uusd")?
-        .amount;
+fn update_allowlist(
+    deps: DepsMut,
+    info: MessageInfo,
+    add: Vec<String>,
+    remove: Vec<String>,
+) -> Result<Response, ContractError> {
     let state = STATE.load(deps.storage)?;
-    let b_luna_balance_response: Cw20BalanceResponse = deps.querier.query_wasm_smart(
-        state.collateral_token.to_string(),
-        &ExternalQueryMsg::Balance {
-            address: env.contract.address.to_string(),
-        },
-    )?;
-    let mut b_luna_balance = b_luna_balance_response.balance;
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
+    if state.owner.to_string().to_lowercase() != info.sender.to_string().to_lowercase() {
+        return Err(Unauthorized {});
+    }
+    if add.is_empty() && remove.is_empty() {
+        return Err(Invalidate {});
+    }
+    for address in add.iter() {
+        ALLOWLIST.save(
+            deps.storage,
+            deps.api
+                .addr_canonicalize(&address.to_lowercase())?
+                .as_slice(),
+            &true,
         )?;
-        for item in &res.bids {
-            usd_balance += Uint128::try_from(item.amount)?;
-            b_luna_balance += Uint128::try_from(item.pending_liquidated_collateral)?;
-        }
-        if res.bids.len() < 31 {
-            break;
-        }
-        start_after = Some(res.bids.last().unwrap().idx);
     }
-    let price_response: PriceResponse = deps.querier.query_wasm_smart(
-        state.price_oracle.to_string(),
-        &ExternalQueryMsg::Price {
-            base: state.collateral_token.to_string(),
-            quote: "uusd".to_string(),
-        },
-    )?;
-    let price = price_response.rate;
-    let total_cap = Uint128::try_from(Uint256::from(b_luna_balance).mul(price))? + usd_balance;
-    Ok(TotalCapResponse { total_cap })
+    for address in remove.iter() {
+        ALLOWLIST.remove(
+            deps.storage,
+            deps.api
+                .addr_canonicalize(&address.to_lowercase())?
+                .as_slice(),
+        );
+    }
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "update_allowlist"),
+        attr("from", info.sender),
+        attr("added", add.len().to_string()),
+        attr("removed", remove.len().to_string()),
+    ]))
 }
 
-fn query_activatable(deps: Deps, env: Env) -> StdResult<ActivatableResponse> {
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    let state = STATE.load(deps.storage)?;
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
-        )?;
-        for item in &res.bids {
-            if item.wait_end.is_some() && item.wait_end.unwrap() < env.block.time.seconds() {
-                return Ok(ActivatableResponse { activatable: true });
-            }
-        }
-        if res.bids.len() < 31 {
-            break;
-        }
-        start_after = Some(res.bids.last().unwrap().idx);
+fn harvest_fees(mut deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+    let mut state = STATE.load(deps.storage)?;
+    let fee_shares = accrue_fees(deps.branch(), &env, &mut state)?;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "harvest_fees"),
+        attr("from", info.sender),
+        attr("fee_recipient", state.fee_recipient),
+        attr("fee_shares", fee_shares),
+    ]))
+}
+
+fn validate_premium_slots(premium_slots: Vec<u8>) -> Result<Vec<u8>, ContractError> {
+    if premium_slots.iter().any(|slot| *slot > MAX_PREMIUM_SLOT) {
+        return Err(Invalidate {});
     }
-    Ok(ActivatableResponse { activatable: false })
+    Ok(premium_slots)
 }
 
-fn query_claimable(deps: Deps, env: Env) -> StdResult<ClaimableResponse> {
-    let mut start_after: Option<Uint128> = Some(Uint128::zero());
-    let state = STATE.load(deps.storage)?;
-    loop {
-        let res: BidsResponse = deps.querier.query_wasm_smart(
-            state.anchor_liquidation_queue.to_string(),
-            &ExternalQueryMsg::BidsByUser {
-                collateral_token: state.collateral_token.to_string(),
-                bidder: env.contract.address.to_string(),
-                start_after,
-                limit: Some(31),
-            },
-        )?;
-        for item in &res.bids {
-            if !item.pending_liquidated_collateral.is_zero() {
-                return Ok(ClaimableResponse { claimable: true });
-            }
-        }
-        if res.bids.len() < 31 {
-            break;
+// Premium slots in range and unique, weights up to 100% and min amount up to max amount
+fn validate_bid_targets(bid_targets: Vec<BidTarget>) -> Result<Vec<BidTarget>, ContractError> {
+    let mut slots = vec![];
+    let mut total_weight = 0u64;
+    for target in bid_targets.iter() {
+        if target.premium_slot > MAX_PREMIUM_SLOT
+            || slots.contains(&target.premium_slot)
+            || target.min_amount > target.max_amount
+        {
+            return Err(Invalidate {});
         }
-        start_after = Some(res.bids.last().unwrap().idx);
+        slots.push(target.premium_slot);
+        total_weight += target.weight;
     }
-    Ok(ClaimableResponse { claimable: false })
+    if total_weight > BPS {
+        return Err(Invalidate {});
+    }
+    Ok(bid_targets)
 }
 
-fn query_permission(deps: Deps, address: String) -> StdResult<PermissionResponse> {
-    let address = deps.api.addr_canonicalize(&address)?;
-    let permission = PERMISSIONS
-        .may_load(deps.storage, address.as_slice())?
-        .unwrap_or(Permission { submit_bid: false });
-    Ok(PermissionResponse { permission })
+fn validate_price_sources(
+    deps: Deps,
+    price_sources: Vec<PriceSource>,
+) -> Result<Vec<PriceSource>, ContractError> {
+    price_sources
+        .into_iter()
+        .map(|source| {
+            Ok(match source {
+                PriceSource::Oracle { contract } => PriceSource::Oracle {
+                    contract: deps.api.addr_validate(contract.as_str())?,
+                },
+                PriceSource::AstroportPool { pair } => PriceSource::AstroportPool {
+                    pair: deps.api.addr_validate(pair.as_str())?,
+                },
+            })
+        })
+        .collect()
 }
 
-fn query_unlockable(deps: Deps, env: Env) -> StdResult<UnlockableResponse> {
-    let mut keys = CLAIM_LIST.keys(deps.storage, None, None, Order::Ascending);
-    let state = STATE.load(deps.storage)?;
-    if let Some(key) = keys.next() {
-        let claim = CLAIM_LIST.load(deps.storage, U32Key::from(key))?;
-        if claim.timestamp.plus_seconds(state.lock_period) <= env.block.time {
-            return Ok(UnlockableResponse { unlockable: true });
-        }
+// Check allowlist when enabled
+// Return true if address can deposit and receive shares in deposit mode
+fn is_allowed(deps: Deps, state: &State, address: &[u8]) -> StdResult<bool> {
+    match state.deposit_mode {
+        DepositMode::Open => Ok(true),
+        DepositMode::Allowlist => Ok(ALLOWLIST.may_load(deps.storage, address)?.is_some()),
     }
-    Ok(UnlockableResponse { unlockable: false })
 }
 
-fn query_last_deposit_timestamp(deps: Deps, address: String) -> StdResult<TimestampResponse> {
-    let address = deps.api.addr_canonicalize(&address)?;
-    let last_timestamp = LAST_DEPOSIT.may_load(deps.storage, address.as_slice())?;
+// Move shares between two addresses
+// Shares of the sender are not transferable while withdraw is locked
+fn move_shares(
+    deps: DepsMut,
+    env: &Env,
+    from: &Addr,
+    to: &Addr,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    if amount.is_zero() {
+        return Err(Invalidate {});
+    }
+    let state = STATE.load(deps.storage)?;
+    let from_raw = deps
+        .api
+        .addr_canonicalize(from.to_string().to_lowercase().as_str())?;
+    let to_raw = deps
+        .api
+        .addr_canonicalize(to.to_string().to_lowercase().as_str())?;
+    let last_timestamp = LAST_DEPOSIT.may_load(deps.storage, from_raw.as_slice())?;
     if let Some(timestamp) = last_timestamp {
-        Ok(TimestampResponse { timestamp })
-    } else {
-        Ok(TimestampResponse {
-            timestamp: Timestamp::default(),
-        })
+        if timestamp.plus_seconds(state.withdraw_lock) >= env.block.time {
+            return Err(Locked {});
+        }
     }
+    let balance = BALANCES
+        .may_load(deps.storage, from_raw.as_slice())?
+        .unwrap_or_default();
+    let locked = locked_share(deps.as_ref(), env, &state, from_raw.as_slice())?;
+    if amount > balance.saturating_sub(locked) && amount <= balance {
+        return Err(Locked {});
+    }
+    if !is_allowed(deps.as_ref(), &state, to_raw.as_slice())? {
+        return Err(Unauthorized {});
+    }
+    BALANCES.update(
+        deps.storage,
+        from_raw.as_slice(),
+        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default().checked_sub(amount)?) },
+    )?;
+    BALANCES.update(deps.storage, to_raw.as_slice(), |balance| -> StdResult<_> {
+        Ok(balance.unwrap_or_default() + amount)
+    })?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
-    use cosmwasm_std::{coins, from_binary, Addr};
+// Reduce allowance of spender on owner's shares
+fn deduct_allowance(
+    deps: DepsMut,
+    env: &Env,
+    owner: &Addr,
+    spender: &Addr,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    let owner_raw = deps
+        .api
+        .addr_canonicalize(owner.to_string().to_lowercase().as_str())?;
+    let spender_raw = deps
+        .api
+        .addr_canonicalize(spender.to_string().to_lowercase().as_str())?;
+    let mut allowance = ALLOWANCES
+        .may_load(deps.storage, (owner_raw.as_slice(), spender_raw.as_slice()))?
+        .unwrap_or_default();
+    if allowance.expires.is_expired(&env.block) || allowance.allowance < amount {
+        return Err(Insufficient {});
+    }
+    allowance.allowance -= amount;
+    ALLOWANCES.save(
+        deps.storage,
+        (owner_raw.as_slice(), spender_raw.as_slice()),
+        &allowance,
+    )?;
+    Ok(())
+}
 
-    #[test]
-    fn proper_initialization() {
-        let mut deps = mock_dependencies(&[]);
-        let msg = InstantiateMsg {
-            owner: Addr::unchecked("owner"),
-            swap_wallet: Addr::unchecked("swap_wallet"),
+fn transfer(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    recipient: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let recipient = deps.api.addr_validate(&recipient)?;
+    move_shares(deps, &env, &info.sender, &recipient, amount)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "transfer"),
+        attr("from", info.sender),
+        attr("to", recipient),
+        attr("amount", amount),
+    ]))
+}
+
+fn send(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    contract: String,
+    amount: Uint128,
+    msg: Binary,
+) -> Result<Response, ContractError> {
+    let contract = deps.api.addr_validate(&contract)?;
+    move_shares(deps, &env, &info.sender, &contract, amount)?;
+    Ok(Response::new()
+        .add_message(
+            Cw20ReceiveMsg {
+                sender: info.sender.to_string(),
+                amount,
+                msg,
+            }
+            .into_cosmos_msg(contract.to_string())?,
+        )
+        .add_attributes(vec![
+            attr("action", "send"),
+            attr("from", info.sender),
+            attr("to", contract),
+            attr("amount", amount),
+        ]))
+}
+
+fn increase_allowance(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    spender: String,
+    amount: Uint128,
+    expires: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    let spender = deps.api.addr_validate(&spender)?;
+    if spender == info.sender {
+        return Err(Invalidate {});
+    }
+    let owner_raw = deps
+        .api
+        .addr_canonicalize(info.sender.to_string().to_lowercase().as_str())?;
+    let spender_raw = deps
+        .api
+        .addr_canonicalize(spender.to_string().to_lowercase().as_str())?;
+    ALLOWANCES.update(
+        deps.storage,
+        (owner_raw.as_slice(), spender_raw.as_slice()),
+        |allowance| -> Result<_, ContractError> {
+            let mut allowance = allowance.unwrap_or_default();
+            if let Some(expires) = expires {
+                if expires.is_expired(&env.block) {
+                    return Err(Invalidate {});
+                }
+                allowance.expires = expires;
+            }
+            allowance.allowance += amount;
+            Ok(allowance)
+        },
+    )?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "increase_allowance"),
+        attr("owner", info.sender),
+        attr("spender", spender),
+        attr("amount", amount),
+    ]))
+}
+
+fn decrease_allowance(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    spender: String,
+    amount: Uint128,
+    expires: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    let spender = deps.api.addr_validate(&spender)?;
+    if spender == info.sender {
+        return Err(Invalidate {});
+    }
+    let owner_raw = deps
+        .api
+        .addr_canonicalize(info.sender.to_string().to_lowercase().as_str())?;
+    let spender_raw = deps
+        .api
+        .addr_canonicalize(spender.to_string().to_lowercase().as_str())?;
+    let key = (owner_raw.as_slice(), spender_raw.as_slice());
+    let mut allowance = ALLOWANCES.may_load(deps.storage, key)?.unwrap_or_default();
+    if amount < allowance.allowance {
+        allowance.allowance -= amount;
+        if let Some(expires) = expires {
+            if expires.is_expired(&env.block) {
+                return Err(Invalidate {});
+            }
+            allowance.expires = expires;
+        }
+        ALLOWANCES.save(deps.storage, key, &allowance)?;
+    } else {
+        ALLOWANCES.remove(deps.storage, key);
+    }
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "decrease_allowance"),
+        attr("owner", info.sender),
+        attr("spender", spender),
+        attr("amount", amount),
+    ]))
+}
+
+fn transfer_from(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    owner: String,
+    recipient: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let owner = deps.api.addr_validate(&owner)?;
+    let recipient = deps.api.addr_validate(&recipient)?;
+    deduct_allowance(deps.branch(), &env, &owner, &info.sender, amount)?;
+    move_shares(deps, &env, &owner, &recipient, amount)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "transfer_from"),
+        attr("from", owner),
+        attr("to", recipient),
+        attr("by", info.sender),
+        attr("amount", amount),
+    ]))
+}
+
+fn burn_from(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    owner: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    if amount.is_zero() {
+        return Err(Invalidate {});
+    }
+    let owner = deps.api.addr_validate(&owner)?;
+    deduct_allowance(deps.branch(), &env, &owner, &info.sender, amount)?;
+    BALANCES.update(
+        deps.storage,
+        deps.api
+            .addr_canonicalize(owner.to_string().to_lowercase().as_str())?
+            .as_slice(),
+        |balance| -> StdResult<_> { Ok(balance.unwrap_or_default().checked_sub(amount)?) },
+    )?;
+    let mut state = STATE.load(deps.storage)?;
+    state.total_supply = state.total_supply.checked_sub(amount)?;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attributes(vec![
+        attr("action", "burn_from"),
+        attr("from", owner),
+        attr("by", info.sender),
+        attr("amount", amount),
+    ]))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(mut deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    settle_management_fee(deps.branch(), &env)?;
+    match msg.id {
+        SWAP_REPLY_ID => swap_reply(deps, env),
+        ZAP_REPLY_ID => zap_reply(deps, env),
+        RETRACT_REPLY_ID => retract_reply(deps, env),
+        _ => Err(Invalidate {}),
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::GetInfo {} => to_binary(&query_info(deps)?),
+        QueryMsg::Config {} => to_binary(&query_config(deps)?),
+        // Get share from address
+        QueryMsg::Balance { address } => to_binary(&query_balance(deps, address)?),
+        // Get total cap in vault and anchor
+        QueryMsg::TotalCap {} => to_binary(&query_total_cap(deps, env)?),
+        // Get assets of vault by kind
+        QueryMsg::NavBreakdown {} => to_binary(&query_nav_breakdown(deps, env)?),
+        // Get freshness and bounds check of oracle price
+        QueryMsg::OracleStatus {} => to_binary(&query_oracle_status(deps, env)?),
+        // Get performance fee settings and fee shares
+        QueryMsg::Fees {} => to_binary(&query_fees(deps, env)?),
+        // Get UST and bLuna not booked by vault
+        QueryMsg::Surplus {} => to_binary(&query_surplus(deps, env)?),
+        // Rank premium slots by premium and UST queued on Anchor
+        QueryMsg::SuggestSlots { amount } => to_binary(&query_suggest_slots(deps, amount)?),
+        // Get UST that address can deposit until deposit caps
+        QueryMsg::RemainingCapacity { address } => {
+            to_binary(&query_remaining_capacity(deps, env, address)?)
+        }
+        QueryMsg::Allowlist { start_after, limit } => {
+            to_binary(&query_allowlist(deps, start_after, limit)?)
+        }
+        // Get slice of assets kept in vault on last pro-rata withdrawal
+        QueryMsg::WithdrawReceipt { address } => to_binary(&query_withdraw_receipt(deps, address)?),
+        // Get tickets and UST waiting in withdraw queue
+        QueryMsg::WithdrawQueue {} => to_binary(&query_withdraw_queue(deps)?),
+        QueryMsg::WithdrawTickets {
+            address,
+            start_after,
+            limit,
+        } => to_binary(&query_withdraw_tickets(deps, address, start_after, limit)?),
+        // Get deposits and redemptions of epoch
+        QueryMsg::Epoch { epoch_id } => to_binary(&query_epoch(deps, epoch_id)?),
+        QueryMsg::EpochRequest { address, epoch_id } => {
+            to_binary(&query_epoch_request(deps, address, epoch_id)?)
+        }
+        // Return true if activate is needed
+        QueryMsg::Activatable {} => to_binary(&query_activatable(deps, env)?),
+        // Return true if liquidate is needed
+        QueryMsg::Claimable {} => to_binary(&query_claimable(deps, env)?),
+        QueryMsg::Permission { address } => to_binary(&query_permission(deps, address)?),
+        QueryMsg::Unlockable {} => to_binary(&query_unlockable(deps, env)?),
+        QueryMsg::LastDepositTimestamp { address } => {
+            to_binary(&query_last_deposit_timestamp(deps, address)?)
+        }
+        QueryMsg::TokenInfo {} => to_binary(&query_token_info(deps)?),
+        QueryMsg::Allowance { owner, spender } => {
+            to_binary(&query_allowance(deps, owner, spender)?)
+        }
+        QueryMsg::AllAccounts { start_after, limit } => {
+            to_binary(&query_all_accounts(deps, start_after, limit)?)
+        }
+        QueryMsg::PreviewDeposit { amount } => {
+            to_binary(&query_preview_deposit(deps, env, amount)?)
+        }
+        QueryMsg::PreviewWithdrawUst { share } => {
+            to_binary(&query_preview_withdraw_ust(deps, env, share)?)
+        }
+        QueryMsg::PreviewWithdrawBLuna { share } => {
+            to_binary(&query_preview_withdraw_b_luna(deps, env, share)?)
+        }
+        QueryMsg::ConvertToShares { assets } => {
+            to_binary(&query_convert_to_shares(deps, env, assets)?)
+        }
+        QueryMsg::ConvertToAssets { shares } => {
+            to_binary(&query_convert_to_assets(deps, env, shares)?)
+        }
+    }
+}
+
+fn query_info(deps: Deps) -> StdResult<InfoResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(InfoResponse {
+        total_supply: state.total_supply,
+        locked_b_luna: state.locked_b_luna,
+        insurance_reserve: state.insurance_reserve,
+        insurance_reserve_b_luna: state.insurance_reserve_b_luna,
+    })
+}
+
+fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(ConfigResponse {
+        owner: state.owner.to_string(),
+        paused: state.paused,
+        swap_wallet: state.swap_wallet.to_string(),
+        anchor_liquidation_queue: state.anchor_liquidation_queue.to_string(),
+        collateral_token: state.collateral_token.to_string(),
+        price_oracle: state.price_oracle.to_string(),
+        astroport_router: state.astroport_router.to_string(),
+        lock_period: state.lock_period,
+        withdraw_lock: state.withdraw_lock,
+        max_price_age: state.max_price_age,
+        min_price: state.min_price,
+        max_price: state.max_price,
+        price_sources: state.price_sources,
+        price_aggregation: state.price_aggregation,
+        performance_fee: state.performance_fee,
+        fee_recipient: state.fee_recipient.to_string(),
+        management_fee: state.management_fee,
+        deposit_fee: state.deposit_fee,
+        withdraw_fee: state.withdraw_fee,
+        max_total_cap: state.max_total_cap,
+        max_user_deposit: state.max_user_deposit,
+        deposit_mode: state.deposit_mode,
+        epoch_mode: state.epoch_mode,
+        epoch_length: state.epoch_length,
+        bid_reserve: state.bid_reserve,
+        bid_targets: state.bid_targets,
+        strategy: state.strategy.map(|strategy| strategy.to_string()),
+        strategy_slots: state.strategy_slots,
+        max_slot_amount: state.max_slot_amount,
+        min_rebalance_delta: state.min_rebalance_delta,
+    })
+}
+
+fn query_balance(deps: Deps, address: String) -> StdResult<BalanceResponse> {
+    let address = deps.api.addr_canonicalize(&address)?;
+    let balance = BALANCES
+        .may_load(deps.storage, address.as_slice())?
+        .unwrap_or_default();
+    Ok(BalanceResponse { balance })
+}
+
+fn query_total_cap(deps: Deps, env: Env) -> StdResult<TotalCapResponse> {
+    let state = STATE.load(deps.storage)?;
+    let total_cap = query_valuation(deps, &env, &state)?.total_cap()?;
+    Ok(TotalCapResponse { total_cap })
+}
+
+fn query_nav_breakdown(deps: Deps, env: Env) -> StdResult<NavBreakdownResponse> {
+    let state = STATE.load(deps.storage)?;
+    let valuation = query_valuation(deps, &env, &state)?;
+    Ok(NavBreakdownResponse {
+        total_cap: valuation.total_cap()?,
+        total_supply: state.total_supply,
+        valuation,
+    })
+}
+
+fn query_oracle_status(deps: Deps, env: Env) -> StdResult<OracleStatusResponse> {
+    let state = STATE.load(deps.storage)?;
+    let price = query_price(deps, &env, &state)?;
+    let age = price_age(&env, price.last_updated);
+    Ok(OracleStatusResponse {
+        price: price.rate,
+        last_updated: price.last_updated,
+        age,
+        max_price_age: state.max_price_age,
+        stale: age > state.max_price_age,
+        in_bounds: price_in_bounds(&state, price.rate),
+        sources: price.sources,
+    })
+}
+
+fn query_fees(deps: Deps, env: Env) -> StdResult<FeesResponse> {
+    let state = STATE.load(deps.storage)?;
+    let total_cap = query_valuation(deps, &env, &state)?.total_cap()?;
+    // Performance fee is charged after management fee
+    let mut accrued_state = state.clone();
+    let pending_management_fees =
+        accrue_management_fee(&mut accrued_state, env.block.time.seconds());
+    Ok(FeesResponse {
+        performance_fee: state.performance_fee,
+        fee_recipient: state.fee_recipient.to_string(),
+        high_water_mark: state.high_water_mark,
+        share_price: share_price(total_cap, accrued_state.total_supply),
+        accrued_fees: state.accrued_fees,
+        pending_fees: performance_fee_shares(&accrued_state, total_cap)?,
+        management_fee: state.management_fee,
+        last_fee_accrual: state.last_fee_accrual,
+        accrued_management_fees: state.accrued_management_fees,
+        pending_management_fees,
+    })
+}
+
+fn query_remaining_capacity(
+    deps: Deps,
+    env: Env,
+    address: String,
+) -> StdResult<RemainingCapacityResponse> {
+    let state = load_accrued_state(deps, &env)?;
+    let total_cap = query_valuation(deps, &env, &state)?.total_cap()?;
+    let balance = query_balance(deps, address)?.balance;
+    let (total, user) = remaining_capacity(&state, total_cap, balance);
+    Ok(RemainingCapacityResponse { total, user })
+}
+
+fn query_withdraw_receipt(deps: Deps, address: String) -> StdResult<WithdrawReceiptResponse> {
+    let address = deps.api.addr_canonicalize(&address.to_lowercase())?;
+    let receipt = WITHDRAW_RECEIPTS.may_load(deps.storage, address.as_slice())?;
+    Ok(WithdrawReceiptResponse { receipt })
+}
+
+fn query_withdraw_queue(deps: Deps) -> StdResult<WithdrawQueueResponse> {
+    let queue = WITHDRAW_QUEUE.may_load(deps.storage)?.unwrap_or_default();
+    Ok(WithdrawQueueResponse {
+        pending: queue.pending,
+        queued_share: queue.queued_share,
+        claimable_ust: queue.claimable_ust,
+        head: queue.head,
+    })
+}
+
+fn query_withdraw_tickets(
+    deps: Deps,
+    address: String,
+    start_after: Option<u32>,
+    limit: Option<u32>,
+) -> StdResult<WithdrawTicketsResponse> {
+    let address = deps.api.addr_canonicalize(&address.to_lowercase())?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive_int);
+    let tickets = USER_TICKETS
+        .prefix(address.as_slice())
+        .keys(deps.storage, start, None, Ascending)
+        .take(limit)
+        .map(|key| {
+            let id = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+            let ticket = WITHDRAW_TICKETS.load(deps.storage, U32Key::from(id))?;
+            Ok(WithdrawTicketResponse {
+                id,
+                share: ticket.share,
+                amount: ticket.amount,
+                fulfilled: ticket.fulfilled,
+                timestamp: ticket.timestamp,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(WithdrawTicketsResponse { tickets })
+}
+
+fn query_epoch(deps: Deps, epoch_id: Option<u32>) -> StdResult<EpochResponse> {
+    let state = STATE.load(deps.storage)?;
+    let id = epoch_id.unwrap_or(state.current_epoch);
+    let epoch = if id == state.current_epoch {
+        load_current_epoch(deps, &state)?
+    } else {
+        EPOCHS.load(deps.storage, U32Key::from(id))?
+    };
+    Ok(EpochResponse {
+        id,
+        start: epoch.start,
+        deposit_ust: epoch.deposit_ust,
+        redeem_share: epoch.redeem_share,
+        minted_share: epoch.minted_share,
+        redeemed_ust: epoch.redeemed_ust,
+        rolled_share: epoch.rolled_share,
+        closed: epoch.closed,
+    })
+}
+
+fn query_epoch_request(
+    deps: Deps,
+    address: String,
+    epoch_id: u32,
+) -> StdResult<EpochRequestResponse> {
+    let address = deps.api.addr_canonicalize(&address.to_lowercase())?;
+    let request = EPOCH_REQUESTS
+        .may_load(deps.storage, (address.as_slice(), U32Key::from(epoch_id)))?
+        .unwrap_or_default();
+    let epoch = EPOCHS
+        .may_load(deps.storage, U32Key::from(epoch_id))?
+        .unwrap_or_default();
+    let (claimable_share, claimable_ust, rolled_share) = epoch_claim(&epoch, &request);
+    Ok(EpochRequestResponse {
+        deposit_ust: request.deposit_ust,
+        redeem_share: request.redeem_share,
+        claimable_share,
+        claimable_ust,
+        rolled_share,
+    })
+}
+
+fn query_surplus(deps: Deps, env: Env) -> StdResult<SurplusResponse> {
+    let state = STATE.load(deps.storage)?;
+    let (ust, b_luna) = surplus(deps, &env, &state)?;
+    Ok(SurplusResponse { ust, b_luna })
+}
+
+// Premium earned per UST of bid, discounted by UST liquidated before or with the bid
+// Premium slots without bid pool have no UST and premium rate of their slot
+fn query_suggest_slots(deps: Deps, amount: Uint128) -> StdResult<SuggestSlotsResponse> {
+    if amount.is_zero() {
+        return Err(StdError::generic_err("Zero amount"));
+    }
+    let state = STATE.load(deps.storage)?;
+    let collateral_info = query_collateral_info(deps, &state)?;
+    let bid_pools = query_bid_pools(deps, &state, collateral_info.premium_rate_per_slot)?;
+    let amount = Uint256::from(amount);
+    let mut queued_ust = Uint256::zero();
+    let mut slots = vec![];
+    for premium_slot in 0..=collateral_info.max_slot {
+        let (premium_rate, slot_ust) =
+            match bid_pools.iter().find(|(slot, _)| *slot == premium_slot) {
+                Some((_, pool)) => (pool.premium_rate, pool.total_bid_amount),
+                None => (
+                    Decimal256::from_ratio(
+                        collateral_info.premium_rate_per_slot.numerator()
+                            * Uint256::from(premium_slot),
+                        collateral_info.premium_rate_per_slot.denominator(),
+                    ),
+                    Uint256::zero(),
+                ),
+            };
+        slots.push(SlotSuggestion {
+            premium_slot,
+            premium_rate,
+            queued_ust,
+            slot_ust,
+            score: Decimal256::from_ratio(
+                premium_rate.numerator() * amount,
+                premium_rate.denominator() * (amount + queued_ust + slot_ust),
+            ),
+        });
+        queued_ust += slot_ust;
+    }
+    // Lower premium slot first on same score, it is liquidated earlier
+    slots.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then(a.premium_slot.cmp(&b.premium_slot))
+    });
+    Ok(SuggestSlotsResponse { slots })
+}
+
+fn query_activatable(deps: Deps, env: Env) -> StdResult<ActivatableResponse> {
+    let state = STATE.load(deps.storage)?;
+    let activatable = query_bids(deps, &env, &state)?
+        .iter()
+        .any(|item| matches!(item.wait_end, Some(wait_end) if wait_end < env.block.time.seconds()));
+    Ok(ActivatableResponse { activatable })
+}
+
+fn query_claimable(deps: Deps, env: Env) -> StdResult<ClaimableResponse> {
+    let state = STATE.load(deps.storage)?;
+    let claimable = query_bids(deps, &env, &state)?
+        .iter()
+        .any(|item| !item.pending_liquidated_collateral.is_zero());
+    Ok(ClaimableResponse { claimable })
+}
+
+fn query_permission(deps: Deps, address: String) -> StdResult<PermissionResponse> {
+    let address = deps.api.addr_canonicalize(&address)?;
+    let permission = PERMISSIONS
+        .may_load(deps.storage, address.as_slice())?
+        .unwrap_or(Permission { submit_bid: false });
+    Ok(PermissionResponse { permission })
+}
+
+fn query_unlockable(deps: Deps, env: Env) -> StdResult<UnlockableResponse> {
+    let mut keys = CLAIM_LIST.keys(deps.storage, None, None, Order::Ascending);
+    let state = STATE.load(deps.storage)?;
+    if let Some(key) = keys.next() {
+        let claim = CLAIM_LIST.load(deps.storage, U32Key::from(key))?;
+        if claim.timestamp.plus_seconds(state.lock_period) <= env.block.time {
+            return Ok(UnlockableResponse { unlockable: true });
+        }
+    }
+    Ok(UnlockableResponse { unlockable: false })
+}
+
+fn query_last_deposit_timestamp(deps: Deps, address: String) -> StdResult<TimestampResponse> {
+    let address = deps.api.addr_canonicalize(&address)?;
+    let last_timestamp = LAST_DEPOSIT.may_load(deps.storage, address.as_slice())?;
+    if let Some(timestamp) = last_timestamp {
+        Ok(TimestampResponse { timestamp })
+    } else {
+        Ok(TimestampResponse {
+            timestamp: Timestamp::default(),
+        })
+    }
+}
+
+fn query_token_info(deps: Deps) -> StdResult<TokenInfoResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(TokenInfoResponse {
+        name: TOKEN_NAME.to_string(),
+        symbol: TOKEN_SYMBOL.to_string(),
+        decimals: TOKEN_DECIMALS,
+        total_supply: state.total_supply,
+    })
+}
+
+fn query_allowance(deps: Deps, owner: String, spender: String) -> StdResult<AllowanceResponse> {
+    let owner = deps.api.addr_canonicalize(&owner)?;
+    let spender = deps.api.addr_canonicalize(&spender)?;
+    let allowance = ALLOWANCES
+        .may_load(deps.storage, (owner.as_slice(), spender.as_slice()))?
+        .unwrap_or_default();
+    Ok(allowance)
+}
+
+fn query_all_accounts(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<AllAccountsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = if let Some(address) = start_after {
+        Some(Bound::exclusive(
+            deps.api.addr_canonicalize(&address)?.as_slice(),
+        ))
+    } else {
+        None
+    };
+    let accounts = BALANCES
+        .keys(deps.storage, start, None, Ascending)
+        .take(limit)
+        .map(|key| Ok(deps.api.addr_humanize(&key.into())?.to_string()))
+        .collect::<StdResult<Vec<String>>>()?;
+    Ok(AllAccountsResponse { accounts })
+}
+
+fn query_allowlist(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<AllowlistResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = if let Some(address) = start_after {
+        Some(Bound::exclusive(
+            deps.api.addr_canonicalize(&address)?.as_slice(),
+        ))
+    } else {
+        None
+    };
+    let addresses = ALLOWLIST
+        .keys(deps.storage, start, None, Ascending)
+        .take(limit)
+        .map(|key| Ok(deps.api.addr_humanize(&key.into())?.to_string()))
+        .collect::<StdResult<Vec<String>>>()?;
+    Ok(AllowlistResponse { addresses })
+}
+
+fn query_preview_deposit(deps: Deps, env: Env, amount: Uint128) -> StdResult<SharesResponse> {
+    let state = load_accrued_state(deps, &env)?;
+    let plan = plan_deposit(deps, &env, &state, amount)
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(SharesResponse { shares: plan.share })
+}
+
+fn query_preview_withdraw_ust(
+    deps: Deps,
+    env: Env,
+    share: Uint128,
+) -> StdResult<PreviewWithdrawUstResponse> {
+    let state = load_accrued_state(deps, &env)?;
+    let plan = plan_withdraw_ust(deps, &env, &state, share)
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(PreviewWithdrawUstResponse {
+        amount: plan.withdraw_cap,
+        fee: plan.fee,
+        ust_amount: plan.ust_amount,
+        retract_bids: !plan.retract_bids.is_empty(),
+        retract_amount: plan.retract_amount,
+        swap_wallet: !plan.swap_b_luna.is_zero(),
+        swap_b_luna: plan.swap_b_luna,
+        unlocked_b_luna: plan.unlocked_b_luna,
+    })
+}
+
+fn query_preview_withdraw_b_luna(
+    deps: Deps,
+    env: Env,
+    share: Uint128,
+) -> StdResult<PreviewWithdrawBLunaResponse> {
+    let state = load_accrued_state(deps, &env)?;
+    let plan = plan_withdraw_b_luna(deps, &env, &state, share)
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(PreviewWithdrawBLunaResponse {
+        amount: plan.withdraw_cap,
+        fee: plan.fee,
+        available: plan.free_b_luna >= plan.withdraw_cap,
+    })
+}
+
+// Ideal conversions without deposit and withdraw fees
+fn query_convert_to_shares(deps: Deps, env: Env, assets: Uint128) -> StdResult<SharesResponse> {
+    let state = load_accrued_state(deps, &env)?;
+    let total_cap = query_valuation(deps, &env, &state)?.total_cap()?;
+    Ok(SharesResponse {
+        shares: convert_to_shares(assets, total_cap, state.total_supply),
+    })
+}
+
+fn query_convert_to_assets(deps: Deps, env: Env, shares: Uint128) -> StdResult<AssetsResponse> {
+    let state = load_accrued_state(deps, &env)?;
+    let total_cap = query_valuation(deps, &env, &state)?.total_cap()?;
+    Ok(AssetsResponse {
+        assets: convert_to_assets(shares, total_cap, state.total_supply),
+    })
+}
+
+// State with fees accrued at current valuation, to preview deposit and withdrawals
+fn load_accrued_state(deps: Deps, env: &Env) -> StdResult<State> {
+    let mut state = STATE.load(deps.storage)?;
+    accrue_state(deps, env, &mut state).map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_querier;
+    use crate::msg::{BidPoolResponse, BidResponse};
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{
+        coins, from_binary, Addr, Api, ContractResult, Decimal256, SubMsgExecutionResponse,
+    };
+
+    fn instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            owner: Addr::unchecked("owner"),
+            swap_wallet: Addr::unchecked("swap_wallet"),
             anchor_liquidation_queue: None,
             collateral_token: None,
             price_oracle: None,
             astroport_router: None,
             lock_period: None,
             withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: None,
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: None,
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: None,
+            strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        }
+    }
+
+    #[test]
+    fn proper_initialization() {
+        let mut deps = mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        let info = mock_info("creator", &coins(1000, "uusd"));
+
+        // we can just call .unwrap() to assert this was a success
+        let res = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        // it worked, let's query the state
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+        let value: ConfigResponse = from_binary(&res).unwrap();
+        assert_eq!("owner", value.owner);
+    }
+
+    #[test]
+    fn share_transfer_and_allowance() {
+        let mut deps = mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let alice = deps.api.addr_canonicalize("alice").unwrap();
+        BALANCES
+            .save(
+                deps.as_mut().storage,
+                alice.as_slice(),
+                &Uint128::from(1000u128),
+            )
+            .unwrap();
+        let mut state = STATE.load(deps.as_ref().storage).unwrap();
+        state.total_supply = Uint128::from(1000u128);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(400u128),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        let msg = ExecuteMsg::IncreaseAllowance {
+            spender: "carol".to_string(),
+            amount: Uint128::from(300u128),
+            expires: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+        let msg = ExecuteMsg::TransferFrom {
+            owner: "bob".to_string(),
+            recipient: "carol".to_string(),
+            amount: Uint128::from(200u128),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("carol", &[]), msg).unwrap();
+        let msg = ExecuteMsg::BurnFrom {
+            owner: "bob".to_string(),
+            amount: Uint128::from(200u128),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("carol", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+        let msg = ExecuteMsg::BurnFrom {
+            owner: "bob".to_string(),
+            amount: Uint128::from(100u128),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("carol", &[]), msg).unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Balance {
+                address: "bob".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(100u128), value.balance);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TokenInfo {}).unwrap();
+        let value: TokenInfoResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(900u128), value.total_supply);
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::AllAccounts {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let value: AllAccountsResponse = from_binary(&res).unwrap();
+        assert_eq!(vec!["alice", "bob", "carol"], value.accounts);
+    }
+
+    fn mock_bid(idx: u128, amount: u128, pending: u128) -> BidResponse {
+        BidResponse {
+            idx: Uint128::from(idx),
+            collateral_token: "b_luna".to_string(),
+            premium_slot: 1,
+            bidder: MOCK_CONTRACT_ADDR.to_string(),
+            amount: Uint256::from(amount),
+            product_snapshot: Decimal256::one(),
+            sum_snapshot: Decimal256::zero(),
+            pending_liquidated_collateral: Uint256::from(pending),
+            wait_end: None,
+            epoch_snapshot: Uint128::zero(),
+            scale_snapshot: Uint128::zero(),
+        }
+    }
+
+    #[test]
+    fn preview_matches_execution() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: Some(Uint128::from(999_000u128)),
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // 600_000 UST in bids, 300_000 bLuna pending at price 2
+        let msg = ExecuteMsg::SubmitBid {
+            amount: Uint128::from(600_000u128),
+            premium_slot: 1,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(1, 600_000, 0), mock_bid(2, 0, 300_000)];
+        deps.querier.price = Decimal256::from_str("2").unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(400_000u128), value.valuation.idle_ust);
+        assert_eq!(Uint128::from(600_000u128), value.valuation.bid_ust);
+        assert_eq!(
+            Uint128::from(300_000u128),
+            value.valuation.pending_collateral
+        );
+        assert_eq!(Uint128::from(1_600_000u128), value.total_cap);
+        assert_eq!(Uint128::from(1_000_000u128), value.total_supply);
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::PreviewWithdrawUst {
+                share: Uint128::from(400_000u128),
+            },
+        )
+        .unwrap();
+        let preview: PreviewWithdrawUstResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(639_760u128), preview.amount);
+        assert!(preview.retract_bids);
+        assert_eq!(Uint128::from(239_760u128), preview.retract_amount);
+        assert!(!preview.swap_wallet);
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1);
+        let msg = ExecuteMsg::WithdrawUst {
+            share: Some(Uint128::from(400_000u128)),
+            min_amount: Some(preview.amount + Uint128::from(1u128)),
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::SlippageExceeded { .. }));
+        let msg = ExecuteMsg::WithdrawUst {
+            share: Some(Uint128::from(400_000u128)),
+            min_amount: Some(preview.amount),
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[1].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(preview.ust_amount.u128(), "uusd"),
+            })
+        );
+
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::PreviewDeposit {
+                amount: Uint128::from(1_000_000u128),
+            },
+        )
+        .unwrap();
+        let preview: SharesResponse = from_binary(&res).unwrap();
+        let msg = ExecuteMsg::Deposit {
+            min_shares: Some(preview.shares),
+            recipient: None,
+        };
+        let res = execute(
+            deps.as_mut(),
+            env,
+            mock_info("bob", &coins(1_000_000, "uusd")),
+            msg,
+        )
+        .unwrap();
+        assert!(res.attributes.contains(&attr("share", preview.shares)));
+    }
+
+    #[test]
+    fn donation_does_not_inflate_share_price() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+        // First deposit must cover dead shares
+        let info = mock_info("attacker", &coins(1_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+
+        // Attacker holds a single share
+        let info = mock_info("attacker", &coins(1_001, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert!(res.attributes.contains(&attr("share", "1")));
+
+        // Attacker donates UST and bLuna to vault directly.
+        // With live balances victim would get 5_000_000 * 1 / 20_001_001 = 0 shares
+        deps.querier
+            .update_balance(coins(1_001 + 10_000_000, "uusd"));
+        deps.querier.b_luna_balance = Uint128::from(10_000_000u128);
+        let info = mock_info("victim", &coins(5_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert!(res.attributes.contains(&attr("share", "5000000")));
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(5_001_001u128), value.total_cap);
+        assert_eq!(Uint128::zero(), value.valuation.free_b_luna);
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ConvertToAssets {
+                shares: Uint128::from(5_000_000u128),
+            },
+        )
+        .unwrap();
+        let value: AssetsResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(5_000_000u128), value.assets);
+    }
+
+    #[test]
+    fn convert_queries_are_fee_free() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.deposit_fee = Some(100);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let assets = Uint128::from(500_000u128);
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ConvertToShares { assets },
+        )
+        .unwrap();
+        let shares: SharesResponse = from_binary(&res).unwrap();
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::PreviewDeposit { amount: assets },
+        )
+        .unwrap();
+        let preview: SharesResponse = from_binary(&res).unwrap();
+        assert!(preview.shares < shares.shares);
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ConvertToAssets {
+                shares: shares.shares,
+            },
+        )
+        .unwrap();
+        let value: AssetsResponse = from_binary(&res).unwrap();
+        assert_eq!(assets, value.assets);
+    }
+
+    #[test]
+    fn performance_fee_over_high_water_mark() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.performance_fee = Some(1_000);
+        msg.fee_recipient = Some(Addr::unchecked("treasury"));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // All UST liquidated into 600_000 bLuna at price 2
+        let msg = ExecuteMsg::SubmitBid {
+            amount: Uint128::from(1_000_000u128),
+            premium_slot: 1,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(1, 0, 600_000)];
+        deps.querier.price = Decimal256::from_str("2").unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Fees {}).unwrap();
+        let value: FeesResponse = from_binary(&res).unwrap();
+        assert_eq!(Decimal256::one(), value.high_water_mark);
+        // 10% of 199_800 UST gain
+        assert_eq!(Uint128::from(16_934u128), value.pending_fees);
+
+        let msg = ExecuteMsg::HarvestFees {};
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+        assert!(res.attributes.contains(&attr("fee_shares", "16934")));
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Fees {}).unwrap();
+        let value: FeesResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(16_934u128), value.accrued_fees);
+        assert_eq!(Uint128::zero(), value.pending_fees);
+        assert_eq!(value.share_price, value.high_water_mark);
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Balance {
+                address: "treasury".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(16_934u128), value.balance);
+
+        // No fee below high-water mark
+        deps.querier.price = Decimal256::from_str("1.9").unwrap();
+        let msg = ExecuteMsg::HarvestFees {};
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+        assert!(res.attributes.contains(&attr("fee_shares", "0")));
+    }
+
+    #[test]
+    fn management_fee_streams_per_second() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.management_fee = Some(200);
+        msg.fee_recipient = Some(Addr::unchecked("treasury"));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(365 * 24 * 60 * 60 / 2);
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::Fees {}).unwrap();
+        let value: FeesResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(10_000u128), value.pending_management_fees);
+
+        // Settled on any message, 2% of total supply a year
+        env.block.time = env.block.time.plus_seconds(365 * 24 * 60 * 60 / 2);
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(1u128),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Balance {
+                address: "treasury".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(20_000u128), value.balance);
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::Fees {}).unwrap();
+        let value: FeesResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::zero(), value.pending_management_fees);
+        assert_eq!(Uint128::from(20_000u128), value.accrued_management_fees);
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: None,
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: Some(501),
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: None,
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: None,
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        };
+        let err = execute(deps.as_mut(), env, mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+    }
+
+    #[test]
+    fn transaction_fees_fund_insurance_reserve() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        msg.deposit_fee = Some(100);
+        msg.withdraw_fee = Some(50);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert!(res.attributes.contains(&attr("share", "989000")));
+        assert!(res.attributes.contains(&attr("fee", "10000")));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1);
+        let msg = ExecuteMsg::WithdrawUst {
+            share: Some(Uint128::from(100_000u128)),
+            min_amount: Some(Uint128::from(99_500u128)),
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(99_500, "uusd"),
+            })
+        );
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::GetInfo {}).unwrap();
+        let value: InfoResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(10_500u128), value.insurance_reserve);
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::TotalCap {}).unwrap();
+        let value: TotalCapResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(890_000u128), value.total_cap);
+
+        let msg = ExecuteMsg::CoverLoss {};
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let msg = ExecuteMsg::CoverLoss {};
+        execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::TotalCap {}).unwrap();
+        let value: TotalCapResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(900_500u128), value.total_cap);
+        let res = query(deps.as_ref(), env, QueryMsg::GetInfo {}).unwrap();
+        let value: InfoResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::zero(), value.insurance_reserve);
+    }
+
+    #[test]
+    fn deposit_caps() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.max_total_cap = Some(Uint128::from(2_000_000u128));
+        msg.max_user_deposit = Some(Uint128::from(1_500_000u128));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::RemainingCapacity {
+                address: "alice".to_string(),
+            },
+        )
+        .unwrap();
+        let value: RemainingCapacityResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(1_000_000u128), value.total);
+        assert_eq!(Uint128::from(501_000u128), value.user);
+
+        let info = mock_info("alice", &coins(600_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::DepositCapReached { remaining } if remaining == Uint128::from(501_000u128)
+        ));
+        let info = mock_info("bob", &coins(1_000_001, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::DepositCapReached { .. }));
+        let info = mock_info("bob", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    }
+
+    #[test]
+    fn allowlist_mode() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        msg.deposit_mode = Some(DepositMode::Allowlist);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let msg = ExecuteMsg::UpdateAllowlist {
+            add: vec!["alice".to_string(), "bob".to_string()],
+            remove: vec![],
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("alice", &[]),
+            msg.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1);
+        let msg = ExecuteMsg::Transfer {
+            recipient: "carol".to_string(),
+            amount: Uint128::from(1u128),
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(1u128),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Allowlist {
+                start_after: Some("alice".to_string()),
+                limit: Some(1),
+            },
+        )
+        .unwrap();
+        let value: AllowlistResponse = from_binary(&res).unwrap();
+        assert_eq!(vec!["bob"], value.addresses);
+        let msg = ExecuteMsg::UpdateAllowlist {
+            add: vec![],
+            remove: vec!["bob".to_string()],
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+        let res = query(
+            deps.as_ref(),
+            env,
+            QueryMsg::Allowlist {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let value: AllowlistResponse = from_binary(&res).unwrap();
+        assert_eq!(vec!["alice"], value.addresses);
+    }
+
+    #[test]
+    fn deposit_for_recipient() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Third party deposit does not reset withdraw lock of alice
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(60 * 60 + 1);
+        deps.querier.last_updated = env.block.time.seconds();
+        let info = mock_info("router", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: Some("alice".to_string()),
+        };
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(999_000u128),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+
+        // Shares credited by third party are locked, they can not be moved or withdrawn at once
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(1u128),
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Locked {}));
+        let msg = ExecuteMsg::WithdrawUst {
+            share: None,
+            min_amount: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Locked {}));
+
+        // New holder is locked
+        let info = mock_info("router", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: Some("carol".to_string()),
+        };
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(1u128),
+        };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("carol", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Locked {}));
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Balance {
+                address: "router".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::zero(), value.balance);
+
+        // Credited shares are unlocked after withdraw lock
+        env.block.time = env.block.time.plus_seconds(60 * 60 + 1);
+        deps.querier.last_updated = env.block.time.seconds();
+        let msg = ExecuteMsg::WithdrawUst {
+            share: None,
+            min_amount: None,
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), env, mock_info("alice", &[]), msg).unwrap();
+        assert!(res
+            .attributes
+            .contains(&attr("share", Uint128::from(1_000_000u128))));
+    }
+
+    #[test]
+    fn withdraw_all_to_recipient() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(60 * 60 + 1);
+        deps.querier.last_updated = env.block.time.seconds();
+        let msg = ExecuteMsg::WithdrawUst {
+            share: None,
+            min_amount: None,
+            recipient: Some("bob".to_string()),
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "bob".to_string(),
+                amount: coins(999_000, "uusd"),
+            })
+        );
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Balance {
+                address: "alice".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::zero(), value.balance);
+
+        // Nothing left to withdraw
+        let msg = ExecuteMsg::WithdrawBLuna {
+            share: None,
+            min_amount: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), env, mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::NoShares {}));
+    }
+
+    #[test]
+    fn withdraw_pro_rata_keeps_locked_slice() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // 400_000 UST idle, 600_000 UST in bids, 300_000 bLuna pending at price 2
+        let msg = ExecuteMsg::SubmitBid {
+            amount: Uint128::from(600_000u128),
+            premium_slot: 1,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(1, 600_000, 0), mock_bid(2, 0, 300_000)];
+        deps.querier.price = Decimal256::from_str("2").unwrap();
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1);
+        let msg = ExecuteMsg::WithdrawProRata {
+            share: Some(Uint128::from(500_000u128)),
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(199_925, "uusd"),
+            })
+        );
+        assert_eq!(1, res.messages.len());
+
+        // A quarter of the shares is burnt for the paid UST, the rest is escrowed
+        let receipt = |deps: Deps, env: &Env| {
+            let res = query(
+                deps,
+                env.clone(),
+                QueryMsg::WithdrawReceipt {
+                    address: "alice".to_string(),
+                },
+            )
+            .unwrap();
+            let value: WithdrawReceiptResponse = from_binary(&res).unwrap();
+            value.receipt
+        };
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Balance {
+                address: "alice".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(499_000u128), value.balance);
+        assert_eq!(
+            Some(WithdrawReceipt {
+                share: Uint128::from(375_000u128),
+                bid_ust: Uint128::from(299_887u128),
+                pending_collateral: Uint128::from(149_943u128),
+                locked_b_luna: Uint128::zero(),
+                timestamp: env.block.time,
+            }),
+            receipt(deps.as_ref(), &env)
+        );
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(200_075u128), value.valuation.idle_ust);
+        assert_eq!(Uint128::from(875_000u128), value.total_supply);
+
+        // Escrowed shares can not be transferred
+        let msg = ExecuteMsg::Transfer {
+            recipient: "bob".to_string(),
+            amount: Uint128::from(500_000u128),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+
+        // Address without receipt has nothing to claim
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("bob", &[]),
+            ExecuteMsg::ClaimReceipt {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NoShares {}));
+
+        // Bid UST is freed, claim pays its slice and keeps pending collateral escrowed
+        let msg = ExecuteMsg::RetractBid {
+            bid_idx: Uint128::from(1u128),
+            amount: None,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.update_balance(coins(600_000, "uusd"));
+        let msg = Reply {
+            id: RETRACT_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        reply(deps.as_mut(), env.clone(), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(2, 0, 300_000)];
+        env.block.time = env.block.time.plus_seconds(1);
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("alice", &[]),
+            ExecuteMsg::ClaimReceipt {},
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(342_741, "uusd"),
+            })
+        );
+        assert!(res
+            .attributes
+            .contains(&attr("share", Uint128::from(214_294u128))));
+        assert_eq!(
+            Some(WithdrawReceipt {
+                share: Uint128::from(160_706u128),
+                bid_ust: Uint128::zero(),
+                pending_collateral: Uint128::from(128_516u128),
+                locked_b_luna: Uint128::zero(),
+                timestamp: env.block.time,
+            }),
+            receipt(deps.as_ref(), &env)
+        );
+
+        // Second withdrawal is settled together with escrowed shares
+        env.block.time = env.block.time.plus_seconds(1);
+        let msg = ExecuteMsg::WithdrawProRata {
+            share: Some(Uint128::from(100_000u128)),
+            recipient: None,
+        };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(180_354, "uusd"),
+            })
+        );
+        assert_eq!(
+            Some(WithdrawReceipt {
+                share: Uint128::from(147_942u128),
+                bid_ust: Uint128::zero(),
+                pending_collateral: Uint128::from(118_309u128),
+                locked_b_luna: Uint128::zero(),
+                timestamp: env.block.time,
+            }),
+            receipt(deps.as_ref(), &env)
+        );
+        let res = query(deps.as_ref(), env, QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(547_942u128), value.total_supply);
+    }
+
+    #[test]
+    fn withdraw_queue() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let msg = ExecuteMsg::SubmitBid {
+            amount: Uint128::from(900_000u128),
+            premium_slot: 1,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(1, 900_000, 0)];
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1);
+        for share in [400_000u128, 100_000, 50_000] {
+            let msg = ExecuteMsg::RequestWithdraw {
+                share: Some(Uint128::from(share)),
+            };
+            execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        }
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::WithdrawTickets {
+                address: "alice".to_string(),
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let value: WithdrawTicketsResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            vec![0, 1, 2],
+            value.tickets.iter().map(|t| t.id).collect::<Vec<_>>()
+        );
+        let msg = ExecuteMsg::CancelWithdraw { ticket_id: 2 };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Balance {
+                address: "alice".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(499_000u128), value.balance);
+
+        // Only permissioned address can fulfil tickets
+        let msg = ExecuteMsg::FulfillWithdraw { limit: None };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // 100_000 idle UST can not cover the first ticket, it is skipped and bids are not retracted
+        let msg = ExecuteMsg::FulfillWithdraw { limit: None };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+        assert!(res.attributes.contains(&attr("tickets", "1")));
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::WithdrawQueue {}).unwrap();
+        let value: WithdrawQueueResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            WithdrawQueueResponse {
+                pending: 1,
+                queued_share: Uint128::from(400_000u128),
+                claimable_ust: Uint128::from(100_000u128),
+                head: 0,
+            },
+            value
+        );
+        let msg = ExecuteMsg::FulfillWithdraw { limit: None };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+
+        // Epoch mode can not be enabled while tickets are pending
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: None,
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: Some(true),
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: None,
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
         };
-        let info = mock_info("creator", &coins(1000, "uusd"));
+        let err = execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::WithdrawPending {}));
 
-        // we can just call .unwrap() to assert this was a success
-        let res = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
-        assert_eq!(0, res.messages.len());
+        let info = mock_info("bob", &coins(500_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+        let msg = ExecuteMsg::FulfillWithdraw { limit: None };
+        execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::WithdrawQueue {}).unwrap();
+        let value: WithdrawQueueResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            WithdrawQueueResponse {
+                pending: 0,
+                queued_share: Uint128::zero(),
+                claimable_ust: Uint128::from(500_000u128),
+                head: 3,
+            },
+            value
+        );
 
-        // it worked, let's query the state
+        let msg = ExecuteMsg::CancelWithdraw { ticket_id: 0 };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::WithdrawFulfilled {}));
+        let msg = ExecuteMsg::ClaimWithdraw { ticket_id: 0 };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("bob", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let msg = ExecuteMsg::ClaimWithdraw { ticket_id: 0 };
+        let res = execute(deps.as_mut(), env, mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(400_000, "uusd"),
+            })
+        );
+    }
+
+    #[test]
+    fn epoch_mode_batches_deposits_and_redemptions() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        msg.epoch_mode = Some(true);
+        msg.epoch_length = Some(100);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::EpochModeEnabled {}));
+        for (user, amount) in [("alice", 1_000_000u128), ("bob", 500_000u128)] {
+            let info = mock_info(user, &coins(amount, "uusd"));
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::RequestDeposit {},
+            )
+            .unwrap();
+        }
+        let msg = ExecuteMsg::ClaimEpoch { epoch_id: 0 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::EpochOpen {}));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(99);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("alice", &[]),
+            ExecuteMsg::CloseEpoch {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            ExecuteMsg::CloseEpoch {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::EpochNotEnded {}));
+        env.block.time = env.block.time.plus_seconds(1);
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            ExecuteMsg::CloseEpoch {},
+        )
+        .unwrap();
+
+        // Both deposits get shares at the same price
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::EpochRequest {
+                address: "bob".to_string(),
+                epoch_id: 0,
+            },
+        )
+        .unwrap();
+        let value: EpochRequestResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(499_666u128), value.claimable_share);
+        let msg = ExecuteMsg::ClaimEpoch { epoch_id: 0 };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(res.attributes[3], attr("share", Uint128::from(999_333u128)));
+
+        let msg = ExecuteMsg::RequestRedeem {
+            share: Some(Uint128::from(300_000u128)),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+        deps.querier.last_updated = env.block.time.seconds();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            ExecuteMsg::CloseEpoch {},
+        )
+        .unwrap();
+        let msg = ExecuteMsg::ClaimEpoch { epoch_id: 1 };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(300_000, "uusd"),
+            })
+        );
+        let res = query(deps.as_ref(), env, QueryMsg::Epoch { epoch_id: None }).unwrap();
+        let value: EpochResponse = from_binary(&res).unwrap();
+        assert_eq!(2, value.id);
+        assert!(!value.closed);
+    }
+
+    #[test]
+    fn epoch_rolls_unpaid_redemptions() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.withdraw_lock = Some(0);
+        msg.epoch_mode = Some(true);
+        msg.epoch_length = Some(100);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::RequestDeposit {},
+        )
+        .unwrap();
+
+        // Epoch mode can not be disabled while epoch holds requests
+        let disable = ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: None,
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: Some(false),
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: None,
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("owner", &[]),
+            disable.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::EpochOpen {}));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(100);
+        deps.querier.last_updated = env.block.time.seconds();
+        let close = ExecuteMsg::CloseEpoch {};
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            close.clone(),
+        )
+        .unwrap();
+        let msg = ExecuteMsg::ClaimEpoch { epoch_id: 0 };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+
+        // Most idle UST goes into bids, redemption is settled only in part
+        let msg = ExecuteMsg::SubmitBid {
+            amount: Uint128::from(800_000u128),
+            premium_slot: 1,
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(1, 800_000, 0)];
+        let msg = ExecuteMsg::RequestRedeem {
+            share: Some(Uint128::from(500_000u128)),
+        };
+        execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+        deps.querier.last_updated = env.block.time.seconds();
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            close.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            res.attributes[5],
+            attr("redeemed_ust", Uint128::from(200_000u128))
+        );
+        assert_eq!(
+            res.attributes[6],
+            attr("rolled_share", Uint128::from(300_000u128))
+        );
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::EpochRequest {
+                address: "alice".to_string(),
+                epoch_id: 1,
+            },
+        )
+        .unwrap();
+        let value: EpochRequestResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(200_000u128), value.claimable_ust);
+        assert_eq!(Uint128::from(300_000u128), value.rolled_share);
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Epoch { epoch_id: None },
+        )
+        .unwrap();
+        let value: EpochResponse = from_binary(&res).unwrap();
+        assert_eq!(2, value.id);
+        assert_eq!(Uint128::from(300_000u128), value.redeem_share);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            disable.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::EpochOpen {}));
+
+        // Rolled shares are moved into request of next epoch on claim
+        let msg = ExecuteMsg::ClaimEpoch { epoch_id: 1 };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(200_000, "uusd"),
+            })
+        );
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::EpochRequest {
+                address: "alice".to_string(),
+                epoch_id: 2,
+            },
+        )
+        .unwrap();
+        let value: EpochRequestResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(300_000u128), value.redeem_share);
+
+        // Deposits of next epoch pay out rolled shares
+        let info = mock_info("bob", &coins(300_000, "uusd"));
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            info,
+            ExecuteMsg::RequestDeposit {},
+        )
+        .unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+        deps.querier.last_updated = env.block.time.seconds();
+        execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), close).unwrap();
+        let msg = ExecuteMsg::ClaimEpoch { epoch_id: 2 };
+        let res = execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), msg).unwrap();
+        assert_eq!(
+            res.attributes[4],
+            attr("amount", Uint128::from(300_000u128))
+        );
+        execute(deps.as_mut(), env, mock_info("owner", &[]), disable).unwrap();
+    }
+
+    #[test]
+    fn deposit_b_luna_through_receive() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.collateral_token = Some(Addr::unchecked("b_luna"));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        deps.querier.price = Decimal256::from_str("2").unwrap();
+
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::from(100_000u128),
+            msg: to_binary(&Cw20HookMsg::DepositBLuna {
+                min_shares: Some(Uint128::from(200_000u128)),
+                recipient: None,
+            })
+            .unwrap(),
+        });
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("fake", &[]),
+            msg.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        execute(deps.as_mut(), mock_env(), mock_info("b_luna", &[]), msg).unwrap();
+
+        // 100_000 bLuna at price 2 mints shares worth 200_000 UST
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Balance {
+                address: "bob".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(200_000u128), value.balance);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(100_000u128), value.valuation.free_b_luna);
+        assert_eq!(Uint128::from(1_200_000u128), value.total_cap);
+    }
+
+    #[test]
+    fn zap_deposit_mints_in_reply() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let msg = ExecuteMsg::ZapDeposit {
+            minimum_receive: Uint128::from(90_000u128),
+            min_shares: None,
+            recipient: None,
+        };
+        let info = mock_info("alice", &coins(1_000, "uusd"));
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+        let info = mock_info("alice", &coins(1_000, "uluna"));
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(ZAP_REPLY_ID, res.messages[0].id);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { funds, .. }) => {
+                assert_eq!(&coins(1_000, "uluna"), funds)
+            }
+            _ => panic!("unexpected message"),
+        }
+
+        // Shares are minted for UST received from router
+        deps.querier.update_balance(coins(100_000, "uusd"));
+        let msg = Reply {
+            id: ZAP_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        reply(deps.as_mut(), mock_env(), msg).unwrap();
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Balance {
+                address: "alice".to_string(),
+            },
+        )
+        .unwrap();
+        let value: BalanceResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(99_000u128), value.balance);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(100_000u128), value.valuation.idle_ust);
+    }
+
+    #[test]
+    fn submit_bids_ladder() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.bid_reserve = Some(Uint128::from(100_000u128));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let bid = |premium_slot: u8, amount: u128| BidEntry {
+            premium_slot,
+            amount: Uint128::from(amount),
+        };
+        for bids in [
+            vec![bid(1, 100_000), bid(1, 100_000)],
+            vec![bid(31, 100_000)],
+            vec![bid(1, 0)],
+            vec![],
+        ] {
+            let msg = ExecuteMsg::SubmitBids { bids };
+            let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+            assert!(matches!(err, ContractError::Invalidate {}));
+        }
+        // Reserve stays idle
+        let msg = ExecuteMsg::SubmitBids {
+            bids: vec![bid(1, 300_000), bid(5, 650_000)],
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+
+        let msg = ExecuteMsg::SubmitBids {
+            bids: vec![bid(1, 300_000), bid(5, 600_000)],
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(
+            res.messages[1].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u".to_string(),
+                funds: coins(600_000, "uusd"),
+                msg: to_binary(&ExternalMsg::SubmitBid {
+                    collateral_token: "terra1kc87mu460fwkqte29rquh4hc20m54fxwtsx7gp".to_string(),
+                    premium_slot: 5,
+                })
+                .unwrap(),
+            })
+        );
+        assert!(res
+            .attributes
+            .contains(&attr("premium_slot_5", Uint128::from(600_000u128))));
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(100_000u128), value.valuation.idle_ust);
+    }
+
+    #[test]
+    fn rebalance_bids_through_reply() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            instantiate_msg(),
+        )
+        .unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let msg = ExecuteMsg::SubmitBid {
+            amount: Uint128::from(600_000u128),
+            premium_slot: 1,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps.querier.bids = vec![mock_bid(1, 400_000, 0), mock_bid(2, 200_000, 0)];
+
+        let msg = ExecuteMsg::RetractBid {
+            bid_idx: Uint128::from(3u128),
+            amount: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+        let msg = ExecuteMsg::RetractBid {
+            bid_idx: Uint128::from(2u128),
+            amount: Some(Uint256::from(300_000u128)),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+        let msg = ExecuteMsg::RebalanceBids {
+            from_slot: 1,
+            to_slot: 10,
+            amount: Uint128::from(700_000u128),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+
+        // First bid fully and second bid partially retracted
+        let msg = ExecuteMsg::RebalanceBids {
+            from_slot: 1,
+            to_slot: 10,
+            amount: Uint128::from(500_000u128),
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(
+            res.messages[1],
+            SubMsg::reply_on_success(
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u".to_string(),
+                    funds: vec![],
+                    msg: to_binary(&ExternalMsg::RetractBid {
+                        bid_idx: Uint128::from(2u128),
+                        amount: Some(Uint256::from(100_000u128)),
+                    })
+                    .unwrap(),
+                }),
+                RETRACT_REPLY_ID,
+            )
+        );
+
+        let msg = Reply {
+            id: RETRACT_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        // Less UST than expected fails the rebalance
+        deps.querier.update_balance(coins(400_000, "uusd"));
+        let err = reply(deps.as_mut(), mock_env(), msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+
+        deps.querier.update_balance(coins(500_000, "uusd"));
+        let res = reply(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u".to_string(),
+                funds: coins(500_000, "uusd"),
+                msg: to_binary(&ExternalMsg::SubmitBid {
+                    collateral_token: "terra1kc87mu460fwkqte29rquh4hc20m54fxwtsx7gp".to_string(),
+                    premium_slot: 10,
+                })
+                .unwrap(),
+            })
+        );
+        deps.querier.bids = vec![mock_bid(2, 100_000, 0), mock_bid(3, 500_000, 0)];
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(400_000u128), value.valuation.idle_ust);
+    }
+
+    #[test]
+    fn rebalance_to_bid_targets() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let target = |premium_slot: u8, weight: u64, max_amount: Uint128| BidTarget {
+            premium_slot,
+            weight,
+            min_amount: Uint128::zero(),
+            max_amount,
+        };
+        let update_targets = |bid_targets: Vec<BidTarget>| ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: None,
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: None,
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: Some(bid_targets),
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        };
+        let slot_bid = |idx: u128, amount: u128, premium_slot: u8| BidResponse {
+            premium_slot,
+            ..mock_bid(idx, amount, 0)
+        };
+        let mut msg = instantiate_msg();
+        msg.bid_targets = Some(vec![
+            target(1, 5_000, Uint128::MAX),
+            target(5, 5_000, Uint128::from(300_000u128)),
+        ]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Weights over 100%
+        let msg = update_targets(vec![
+            target(1, 6_000, Uint128::MAX),
+            target(5, 5_000, Uint128::MAX),
+        ]);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+
+        // Slot 5 is capped at max amount
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap();
+        assert_eq!(2, res.messages.len());
+        assert!(res
+            .attributes
+            .contains(&attr("premium_slot_1", Uint128::from(500_000u128))));
+        assert!(res
+            .attributes
+            .contains(&attr("premium_slot_5", Uint128::from(300_000u128))));
+        deps.querier.bids = vec![slot_bid(1, 500_000, 1), slot_bid(2, 300_000, 5)];
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::BidsBalanced {}));
+
+        // Bids of slot 1 move to slot 5 with idle UST
+        let msg = update_targets(vec![target(5, 10_000, Uint128::MAX)]);
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_on_success(
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u".to_string(),
+                    funds: vec![],
+                    msg: to_binary(&ExternalMsg::RetractBid {
+                        bid_idx: Uint128::from(1u128),
+                        amount: None,
+                    })
+                    .unwrap(),
+                }),
+                RETRACT_REPLY_ID,
+            )]
+        );
+        deps.querier.update_balance(coins(500_000, "uusd"));
+        let msg = Reply {
+            id: RETRACT_REPLY_ID,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u".to_string(),
+                funds: coins(700_000, "uusd"),
+                msg: to_binary(&ExternalMsg::SubmitBid {
+                    collateral_token: "terra1kc87mu460fwkqte29rquh4hc20m54fxwtsx7gp".to_string(),
+                    premium_slot: 5,
+                })
+                .unwrap(),
+            })
+        );
+        deps.querier.bids = vec![slot_bid(2, 300_000, 5), slot_bid(3, 700_000, 5)];
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::zero(), value.valuation.idle_ust);
+    }
+
+    #[test]
+    fn rebalance_skips_moves_under_min_delta() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let target = |weight: u64| BidTarget {
+            premium_slot: 1,
+            weight,
+            min_amount: Uint128::zero(),
+            max_amount: Uint128::MAX,
+        };
+        let update_targets = |bid_targets: Vec<BidTarget>| ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: None,
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: None,
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: Some(bid_targets),
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        };
+        let mut msg = instantiate_msg();
+        msg.bid_targets = Some(vec![target(10_000)]);
+        msg.min_rebalance_delta = Some(Uint128::from(50_000u128));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap();
+        assert!(res
+            .attributes
+            .contains(&attr("premium_slot_1", Uint128::from(1_000_000u128))));
+        deps.querier.bids = vec![BidResponse {
+            premium_slot: 1,
+            ..mock_bid(1, 1_000_000, 0)
+        }];
+
+        // Excess of 30000 is under min delta
+        let msg = update_targets(vec![target(9_700)]);
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::BidsBalanced {}));
+        assert!(BID_RETRACTION.may_load(&deps.storage).unwrap().is_none());
+
+        // Excess of 100000 is retracted
+        let msg = update_targets(vec![target(9_000)]);
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::reply_on_success(
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: "terra1e25zllgag7j9xsun3me4stnye2pcg66234je3u".to_string(),
+                    funds: vec![],
+                    msg: to_binary(&ExternalMsg::RetractBid {
+                        bid_idx: Uint128::from(1u128),
+                        amount: Some(Uint256::from(100_000u128)),
+                    })
+                    .unwrap(),
+                }),
+                RETRACT_REPLY_ID,
+            )]
+        );
+    }
+
+    #[test]
+    fn rebalance_by_strategy_within_guardrails() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let bid = |premium_slot: u8, amount: u128| BidEntry {
+            premium_slot,
+            amount: Uint128::from(amount),
+        };
+        let mut msg = instantiate_msg();
+        msg.bid_reserve = Some(Uint128::from(100_000u128));
+        msg.strategy = Some(Addr::unchecked("strategy"));
+        msg.strategy_slots = Some(vec![1, 5]);
+        msg.max_slot_amount = Some(Uint128::from(600_000u128));
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Slot not allowed and target over max slot amount
+        for strategy_bids in [vec![bid(3, 100_000)], vec![bid(1, 700_000)]] {
+            deps.querier.strategy_bids = strategy_bids;
+            let err = execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("keeper", &[]),
+                ExecuteMsg::Rebalance {},
+            )
+            .unwrap_err();
+            assert!(matches!(err, ContractError::GuardrailViolated {}));
+        }
+
+        // Bid reserve stays idle
+        deps.querier.strategy_bids = vec![bid(1, 400_000), bid(5, 600_000)];
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap();
+        assert_eq!(2, res.messages.len());
+        assert!(res
+            .attributes
+            .contains(&attr("premium_slot_5", Uint128::from(500_000u128))));
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(100_000u128), value.valuation.idle_ust);
+
+        // Strategy cannot be set and removed at once
+        let update_strategy =
+            |strategy: Option<String>, clear_strategy: Option<bool>| ExecuteMsg::UpdateConfig {
+                owner: None,
+                paused: None,
+                swap_wallet: None,
+                lock_period: None,
+                withdraw_lock: None,
+                max_price_age: None,
+                min_price: None,
+                max_price: None,
+                price_sources: None,
+                price_aggregation: None,
+                performance_fee: None,
+                fee_recipient: None,
+                management_fee: None,
+                deposit_fee: None,
+                withdraw_fee: None,
+                max_total_cap: None,
+                max_user_deposit: None,
+                deposit_mode: None,
+                epoch_mode: None,
+                epoch_length: None,
+                bid_reserve: None,
+                bid_targets: None,
+                strategy,
+                clear_strategy,
+                strategy_slots: None,
+                max_slot_amount: None,
+                min_rebalance_delta: None,
+            };
+        let msg = update_strategy(Some("strategy".to_string()), Some(true));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+
+        // Strategy is removed, no bid targets are set
+        let msg = update_strategy(None, Some(true));
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
         let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
         let value: ConfigResponse = from_binary(&res).unwrap();
-        assert_eq!("owner", value.owner);
+        assert_eq!(None, value.strategy);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::Rebalance {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Invalidate {}));
+    }
+
+    #[test]
+    fn suggest_slots_by_premium_and_queue() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            instantiate_msg(),
+        )
+        .unwrap();
+        let pool = |total_bid_amount: u128, premium: u64| BidPoolResponse {
+            sum_snapshot: Decimal256::zero(),
+            product_snapshot: Decimal256::one(),
+            total_bid_amount: Uint256::from(total_bid_amount),
+            premium_rate: Decimal256::percent(premium),
+            current_epoch: Uint128::zero(),
+            current_scale: Uint128::zero(),
+        };
+        // No pool of slot 3 yet
+        deps.querier.collateral_info.max_slot = 3;
+        deps.querier.bid_pools = vec![pool(1_000_000, 0), pool(0, 1), pool(4_000_000, 2)];
+
+        let msg = QueryMsg::SuggestSlots {
+            amount: Uint128::zero(),
+        };
+        assert!(query(deps.as_ref(), mock_env(), msg).is_err());
+        let msg = QueryMsg::SuggestSlots {
+            amount: Uint128::from(1_000_000u128),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let value: SuggestSlotsResponse = from_binary(&res).unwrap();
+        let ranked: Vec<u8> = value.slots.iter().map(|slot| slot.premium_slot).collect();
+        assert_eq!(vec![1, 3, 2, 0], ranked);
+        assert_eq!(
+            SlotSuggestion {
+                premium_slot: 3,
+                premium_rate: Decimal256::percent(3),
+                queued_ust: Uint256::from(5_000_000u128),
+                slot_ust: Uint256::zero(),
+                score: Decimal256::permille(5),
+            },
+            value.slots[1]
+        );
+    }
+
+    #[test]
+    fn suggest_slots_with_missing_bid_pools() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            instantiate_msg(),
+        )
+        .unwrap();
+        let pool = |total_bid_amount: u128, premium: u64| BidPoolResponse {
+            sum_snapshot: Decimal256::zero(),
+            product_snapshot: Decimal256::one(),
+            total_bid_amount: Uint256::from(total_bid_amount),
+            premium_rate: Decimal256::percent(premium),
+            current_epoch: Uint128::zero(),
+            current_scale: Uint128::zero(),
+        };
+        // Only pools of slots 0, 3 and 7 exist
+        deps.querier.collateral_info.max_slot = 8;
+        deps.querier.bid_pools = vec![pool(1_000_000, 0), pool(2_000_000, 3), pool(3_000_000, 7)];
+
+        let msg = QueryMsg::SuggestSlots {
+            amount: Uint128::from(1_000_000u128),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let value: SuggestSlotsResponse = from_binary(&res).unwrap();
+        assert_eq!(9, value.slots.len());
+        let slot = |premium_slot: u8| {
+            value
+                .slots
+                .iter()
+                .find(|slot| slot.premium_slot == premium_slot)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(Uint256::zero(), slot(1).slot_ust);
+        assert_eq!(Uint256::from(1_000_000u128), slot(1).queued_ust);
+        assert_eq!(Decimal256::percent(3), slot(3).premium_rate);
+        assert_eq!(Uint256::from(2_000_000u128), slot(3).slot_ust);
+        assert_eq!(Uint256::from(3_000_000u128), slot(6).queued_ust);
+        assert_eq!(Decimal256::percent(7), slot(7).premium_rate);
+        assert_eq!(Uint256::from(3_000_000u128), slot(7).slot_ust);
+        assert_eq!(Uint256::from(6_000_000u128), slot(8).queued_ust);
+    }
+
+    #[test]
+    fn sync_and_skim_surplus() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            instantiate_msg(),
+        )
+        .unwrap();
+        let info = mock_info("alice", &coins(1_000_000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Sent directly to vault, not booked
+        deps.querier.update_balance(coins(1_005_000, "uusd"));
+        deps.querier.b_luna_balance = Uint128::from(7_000u128);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Surplus {}).unwrap();
+        let value: SurplusResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            SurplusResponse {
+                ust: Uint128::from(5_000u128),
+                b_luna: Uint128::from(7_000u128),
+            },
+            value
+        );
+
+        let msg = ExecuteMsg::Skim {
+            recipient: "bob".to_string(),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let msg = ExecuteMsg::Skim {
+            recipient: "bob".to_string(),
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "bob".to_string(),
+                amount: coins(5_000, "uusd"),
+            })
+        );
+        assert_eq!(2, res.messages.len());
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("alice", &[]),
+            ExecuteMsg::Sync {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("owner", &[]),
+            ExecuteMsg::Sync {},
+        )
+        .unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::NavBreakdown {}).unwrap();
+        let value: NavBreakdownResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::from(7_000u128), value.valuation.free_b_luna);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Surplus {}).unwrap();
+        let value: SurplusResponse = from_binary(&res).unwrap();
+        assert_eq!(Uint128::zero(), value.ust);
+        assert_eq!(Uint128::zero(), value.b_luna);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("owner", &[]),
+            ExecuteMsg::Sync {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Insufficient {}));
+    }
+
+    #[test]
+    fn stale_price() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let msg = instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        deps.querier.update_balance(coins(1000, "uusd"));
+        deps.querier.last_updated -= 10 * 60 + 1;
+        let info = mock_info("alice", &coins(1000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::StalePrice { .. }));
+
+        deps.querier.last_updated = mock_env().block.time.seconds();
+        deps.querier.price = Decimal256::zero();
+        let info = mock_info("alice", &coins(1000, "uusd"));
+        let msg = ExecuteMsg::Deposit {
+            min_shares: None,
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::PriceOutOfBounds { .. }));
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap();
+        let value: OracleStatusResponse = from_binary(&res).unwrap();
+        assert!(!value.stale);
+        assert!(!value.in_bounds);
+    }
+
+    #[test]
+    fn price_fallback_and_median() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.price_oracle = Some(Addr::unchecked("oracle"));
+        msg.price_sources = Some(vec![
+            PriceSource::Oracle {
+                contract: Addr::unchecked("oracle2"),
+            },
+            PriceSource::AstroportPool {
+                pair: Addr::unchecked("pair"),
+            },
+        ]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        deps.querier
+            .oracle_prices
+            .insert("oracle".to_string(), Decimal256::from_str("100").unwrap());
+        deps.querier
+            .oracle_prices
+            .insert("oracle2".to_string(), Decimal256::from_str("90").unwrap());
+        deps.querier.pool_rate = Decimal256::from_str("1.02").unwrap();
+        deps.querier.luna_price = Decimal256::from_str("100").unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap();
+        let value: OracleStatusResponse = from_binary(&res).unwrap();
+        assert_eq!(Decimal256::from_str("100").unwrap(), value.price);
+        assert_eq!(1, value.sources.len());
+
+        // primary out of bounds falls back to second oracle
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: Some(Decimal256::from_str("99").unwrap()),
+            price_sources: None,
+            price_aggregation: None,
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: None,
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: None,
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap();
+        let value: OracleStatusResponse = from_binary(&res).unwrap();
+        assert_eq!(Decimal256::from_str("90").unwrap(), value.price);
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            paused: None,
+            swap_wallet: None,
+            lock_period: None,
+            withdraw_lock: None,
+            max_price_age: None,
+            min_price: None,
+            max_price: Some(Decimal256::MAX),
+            price_sources: None,
+            price_aggregation: Some(PriceAggregation::Median),
+            performance_fee: None,
+            fee_recipient: None,
+            management_fee: None,
+            deposit_fee: None,
+            withdraw_fee: None,
+            max_total_cap: None,
+            max_user_deposit: None,
+            deposit_mode: None,
+            epoch_mode: None,
+            epoch_length: None,
+            bid_reserve: None,
+            bid_targets: None,
+            strategy: None,
+            clear_strategy: None,
+            strategy_slots: None,
+            max_slot_amount: None,
+            min_rebalance_delta: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap();
+        let value: OracleStatusResponse = from_binary(&res).unwrap();
+        assert_eq!(Decimal256::from_str("100").unwrap(), value.price);
+        assert_eq!(3, value.sources.len());
+    }
+
+    #[test]
+    fn price_primary_error_falls_through() {
+        let mut deps = mock_querier::mock_dependencies(&[]);
+        let mut msg = instantiate_msg();
+        msg.price_oracle = Some(Addr::unchecked("oracle"));
+        msg.price_sources = Some(vec![
+            PriceSource::Oracle {
+                contract: Addr::unchecked("oracle2"),
+            },
+            PriceSource::AstroportPool {
+                pair: Addr::unchecked("pair"),
+            },
+        ]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        deps.querier.failing_oracles = vec!["oracle".to_string()];
+        deps.querier
+            .oracle_prices
+            .insert("oracle2".to_string(), Decimal256::from_str("90").unwrap());
+        deps.querier.pool_rate = Decimal256::from_str("1.02").unwrap();
+        deps.querier.luna_price = Decimal256::from_str("100").unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap();
+        let value: OracleStatusResponse = from_binary(&res).unwrap();
+        assert_eq!(Decimal256::from_str("90").unwrap(), value.price);
+        assert_eq!(1, value.sources.len());
+
+        // Both oracles fail, pool price is not used alone
+        deps.querier.failing_oracles.push("oracle2".to_string());
+        query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap_err();
+    }
+
+    #[test]
+    fn lone_pool_price_is_rejected() {
+        for price_aggregation in [PriceAggregation::Fallback, PriceAggregation::Median] {
+            let mut deps = mock_querier::mock_dependencies(&[]);
+            let mut msg = instantiate_msg();
+            msg.price_sources = Some(vec![PriceSource::AstroportPool {
+                pair: Addr::unchecked("pair"),
+            }]);
+            msg.price_aggregation = Some(price_aggregation);
+            instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+            deps.querier.price = Decimal256::from_str("100").unwrap();
+            deps.querier.luna_price = Decimal256::from_str("100").unwrap();
+
+            // Pool price moved by a swap while oracle is stale
+            deps.querier.last_updated -= 10 * 60 + 1;
+            deps.querier.pool_rate = Decimal256::from_str("1.5").unwrap();
+            let res = query(deps.as_ref(), mock_env(), QueryMsg::OracleStatus {}).unwrap();
+            let value: OracleStatusResponse = from_binary(&res).unwrap();
+            assert_eq!(Decimal256::from_str("100").unwrap(), value.price);
+            assert!(value.stale);
+            let info = mock_info("alice", &coins(1000, "uusd"));
+            let msg = ExecuteMsg::Deposit {
+                min_shares: None,
+                recipient: None,
+            };
+            let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+            assert!(matches!(err, ContractError::StalePrice { .. }));
+        }
     }
 }
@@ -1,4 +1,4 @@
-use cosmwasm_std::{ConversionOverflowError, OverflowError, StdError};
+use cosmwasm_std::{ConversionOverflowError, Decimal256, OverflowError, StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -27,8 +27,47 @@ pub enum ContractError {
     #[error("Locked")]
     Locked {},
 
+    #[error("No Shares")]
+    NoShares {},
+
+    #[error("Bids Balanced")]
+    BidsBalanced {},
+
+    #[error("Guardrail Violated")]
+    GuardrailViolated {},
+
+    #[error("Withdraw Pending")]
+    WithdrawPending {},
+
+    #[error("Withdraw Fulfilled")]
+    WithdrawFulfilled {},
+
+    #[error("Epoch Mode Enabled")]
+    EpochModeEnabled {},
+
+    #[error("Epoch Mode Disabled")]
+    EpochModeDisabled {},
+
+    #[error("Epoch Open")]
+    EpochOpen {},
+
+    #[error("Epoch Not Ended")]
+    EpochNotEnded {},
+
     #[error("Paused")]
     Paused {},
+
+    #[error("Slippage: expected at least {expected}, got {actual}")]
+    SlippageExceeded { expected: Uint128, actual: Uint128 },
+
+    #[error("Stale Price: updated {age}s ago, max age {max_age}s")]
+    StalePrice { age: u64, max_age: u64 },
+
+    #[error("Price Out Of Bounds: {price}")]
+    PriceOutOfBounds { price: Decimal256 },
+
+    #[error("Deposit Cap Reached: {remaining} remaining")]
+    DepositCapReached { remaining: Uint128 },
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
 }
@@ -0,0 +1,118 @@
+use cosmwasm_std::{Decimal256, Deps, DepsMut, Env, StdResult, Uint128, Uint256};
+use std::convert::TryFrom;
+
+use crate::error::ContractError;
+use crate::state::{State, BALANCES, STATE};
+use crate::valuation::{query_valuation, VIRTUAL_ASSETS, VIRTUAL_SHARES};
+
+// Basis points of 100%
+pub const BPS: u64 = 10_000;
+// Highest performance fee owner can set, 50%
+pub const MAX_PERFORMANCE_FEE: u64 = 5_000;
+// Highest annual management fee owner can set, 5%
+pub const MAX_MANAGEMENT_FEE: u64 = 500;
+// Highest deposit and withdraw fee owner can set, 5%
+pub const MAX_TRANSACTION_FEE: u64 = 500;
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+// Fee in bps of amount
+pub fn fee_amount(amount: Uint128, fee: u64) -> Uint128 {
+    amount.multiply_ratio(fee, BPS)
+}
+
+// UST value of one share with virtual shares and assets
+pub fn share_price(total_cap: Uint128, total_supply: Uint128) -> Decimal256 {
+    Decimal256::from_ratio(
+        total_cap.u128() + VIRTUAL_ASSETS,
+        total_supply.u128() + VIRTUAL_SHARES,
+    )
+}
+
+// Management fee shares for seconds since last accrual
+pub fn management_fee_shares(state: &State, now: u64) -> Uint128 {
+    let elapsed = now.saturating_sub(state.last_fee_accrual);
+    state.total_supply.multiply_ratio(
+        state.management_fee as u128 * elapsed as u128,
+        BPS as u128 * SECONDS_PER_YEAR as u128,
+    )
+}
+
+// Add management fee shares to total supply and move last accrual time
+// Returns fee shares to mint to fee recipient
+pub fn accrue_management_fee(state: &mut State, now: u64) -> Uint128 {
+    let fee_shares = management_fee_shares(state, now);
+    state.total_supply += fee_shares;
+    state.accrued_management_fees += fee_shares;
+    state.last_fee_accrual = now;
+    fee_shares
+}
+
+// Fee shares to mint for share price above high-water mark
+pub fn performance_fee_shares(state: &State, total_cap: Uint128) -> StdResult<Uint128> {
+    let price = share_price(total_cap, state.total_supply);
+    if state.performance_fee == 0 || price <= state.high_water_mark {
+        return Ok(Uint128::zero());
+    }
+    let profit = Uint256::from(state.total_supply) * (price - state.high_water_mark);
+    let fee_amount =
+        Uint128::try_from(profit * Decimal256::from_ratio(state.performance_fee, BPS))?;
+    // Fee shares are worth fee amount after mint
+    Ok(fee_amount.multiply_ratio(
+        state.total_supply.u128() + VIRTUAL_SHARES,
+        total_cap.u128() + VIRTUAL_ASSETS - fee_amount.u128(),
+    ))
+}
+
+// Add performance fee shares to total supply and raise high-water mark
+// Returns fee shares to mint to fee recipient
+pub fn accrue_performance_fee(state: &mut State, total_cap: Uint128) -> StdResult<Uint128> {
+    let fee_shares = performance_fee_shares(state, total_cap)?;
+    state.total_supply += fee_shares;
+    state.accrued_fees += fee_shares;
+    let price = share_price(total_cap, state.total_supply);
+    if price > state.high_water_mark {
+        state.high_water_mark = price;
+    }
+    Ok(fee_shares)
+}
+
+// Accrue management and performance fees on state at current valuation without minting
+// Used by previews
+pub fn accrue_state(deps: Deps, env: &Env, state: &mut State) -> Result<Uint128, ContractError> {
+    let management_fee = accrue_management_fee(state, env.block.time.seconds());
+    let valuation = query_valuation(deps, env, state)?;
+    valuation.check_price(env, state)?;
+    Ok(management_fee + accrue_performance_fee(state, valuation.total_cap()?)?)
+}
+
+// Accrue fees at current valuation and mint fee shares to fee recipient
+// Caller saves state
+pub fn accrue_fees(deps: DepsMut, env: &Env, state: &mut State) -> Result<Uint128, ContractError> {
+    let fee_shares = accrue_state(deps.as_ref(), env, state)?;
+    mint_fee_shares(deps, state, fee_shares)?;
+    Ok(fee_shares)
+}
+
+// Settle management fee up to current block, before any state change
+pub fn settle_management_fee(deps: DepsMut, env: &Env) -> StdResult<()> {
+    let mut state = STATE.load(deps.storage)?;
+    if state.last_fee_accrual == env.block.time.seconds() {
+        return Ok(());
+    }
+    let fee_shares = accrue_management_fee(&mut state, env.block.time.seconds());
+    STATE.save(deps.storage, &state)?;
+    mint_fee_shares(deps, &state, fee_shares)
+}
+
+fn mint_fee_shares(deps: DepsMut, state: &State, fee_shares: Uint128) -> StdResult<()> {
+    if !fee_shares.is_zero() {
+        BALANCES.update(
+            deps.storage,
+            deps.api
+                .addr_canonicalize(&state.fee_recipient.to_string().to_lowercase())?
+                .as_slice(),
+            |balance| -> StdResult<_> { Ok(balance.unwrap_or_default() + fee_shares) },
+        )?;
+    }
+    Ok(())
+}
@@ -1,6 +1,12 @@
 pub mod contract;
 mod error;
+pub mod fees;
 pub mod msg;
+pub mod oracle;
 pub mod state;
+pub mod valuation;
+
+#[cfg(test)]
+mod mock_querier;
 
 pub use crate::error::ContractError;
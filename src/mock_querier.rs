@@ -0,0 +1,176 @@
+use cosmwasm_std::testing::{mock_env, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+use cosmwasm_std::{
+    from_binary, from_slice, to_binary, Coin, ContractResult, Decimal256, Empty, OwnedDeps,
+    Querier, QuerierResult, QueryRequest, SystemError, SystemResult, Uint128, Uint256, WasmQuery,
+};
+
+use crate::msg::{
+    BidEntry, BidPoolResponse, BidPoolsResponse, BidResponse, BidsResponse, CollateralInfoResponse,
+    Cw20BalanceResponse, ExternalQueryMsg, NextBidsResponse, PriceResponse,
+    SimulateSwapOperationsResponse, SimulationResponse, StrategyQuery,
+};
+use crate::valuation::pool_slot;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+pub fn mock_dependencies(
+    contract_balance: &[Coin],
+) -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: WasmMockQuerier::new(MockQuerier::new(&[(MOCK_CONTRACT_ADDR, contract_balance)])),
+    }
+}
+
+// Mock of bLuna token, Anchor liquidation queue and price oracle
+pub struct WasmMockQuerier {
+    base: MockQuerier<Empty>,
+    pub b_luna_balance: Uint128,
+    pub bids: Vec<BidResponse>,
+    pub price: Decimal256,
+    pub last_updated: u64,
+    // Price of other oracle contracts
+    pub oracle_prices: HashMap<String, Decimal256>,
+    // Oracle contracts failing price query
+    pub failing_oracles: Vec<String>,
+    // LUNA for 1 bLuna on Astroport pair and UST for 1 LUNA
+    pub pool_rate: Decimal256,
+    pub luna_price: Decimal256,
+    // Targets returned by strategy contract
+    pub strategy_bids: Vec<BidEntry>,
+    // Anchor bid pools of bLuna in premium slot order and collateral info
+    pub bid_pools: Vec<BidPoolResponse>,
+    pub collateral_info: CollateralInfoResponse,
+}
+
+impl Querier for WasmMockQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        self.handle_query(&request)
+    }
+}
+
+impl WasmMockQuerier {
+    pub fn new(base: MockQuerier<Empty>) -> Self {
+        WasmMockQuerier {
+            base,
+            b_luna_balance: Uint128::zero(),
+            bids: vec![],
+            price: Decimal256::one(),
+            last_updated: mock_env().block.time.seconds(),
+            oracle_prices: HashMap::new(),
+            failing_oracles: vec![],
+            pool_rate: Decimal256::one(),
+            luna_price: Decimal256::one(),
+            strategy_bids: vec![],
+            bid_pools: vec![],
+            collateral_info: CollateralInfoResponse {
+                collateral_token: "b_luna".to_string(),
+                bid_threshold: Uint256::from(500_000_000u128),
+                max_slot: 30,
+                premium_rate_per_slot: Decimal256::percent(1),
+            },
+        }
+    }
+
+    pub fn update_balance(&mut self, balance: Vec<Coin>) {
+        self.base.update_balance(MOCK_CONTRACT_ADDR, balance);
+    }
+
+    pub fn handle_query(&self, request: &QueryRequest<Empty>) -> QuerierResult {
+        match request {
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg }) => {
+                if let Ok(StrategyQuery::NextBids { .. }) = from_binary(msg) {
+                    return SystemResult::Ok(ContractResult::from(to_binary(&NextBidsResponse {
+                        bids: self.strategy_bids.clone(),
+                    })));
+                }
+                let res = match from_binary(msg).unwrap() {
+                    ExternalQueryMsg::Balance { .. } => to_binary(&Cw20BalanceResponse {
+                        balance: self.b_luna_balance,
+                    }),
+                    ExternalQueryMsg::BidsByUser {
+                        start_after, limit, ..
+                    } => {
+                        let start_after = start_after.unwrap_or_default();
+                        let limit = limit.unwrap_or(10) as usize;
+                        to_binary(&BidsResponse {
+                            bids: self
+                                .bids
+                                .iter()
+                                .filter(|bid| bid.idx > start_after)
+                                .take(limit)
+                                .cloned()
+                                .collect(),
+                        })
+                    }
+                    ExternalQueryMsg::Price { .. }
+                        if self.failing_oracles.contains(contract_addr) =>
+                    {
+                        return SystemResult::Ok(ContractResult::Err(
+                            "Price not found".to_string(),
+                        ));
+                    }
+                    ExternalQueryMsg::Price { .. } => to_binary(&PriceResponse {
+                        rate: *self.oracle_prices.get(contract_addr).unwrap_or(&self.price),
+                        last_updated_base: self.last_updated,
+                        last_updated_quote: u64::MAX,
+                    }),
+                    ExternalQueryMsg::Simulation { offer_asset } => {
+                        to_binary(&SimulationResponse {
+                            return_amount: Uint128::try_from(
+                                Uint256::from(offer_asset.amount) * self.pool_rate,
+                            )
+                            .unwrap(),
+                            spread_amount: Uint128::zero(),
+                            commission_amount: Uint128::zero(),
+                        })
+                    }
+                    ExternalQueryMsg::BidPoolsByCollateral {
+                        start_after, limit, ..
+                    } => {
+                        // Pools are keyed by premium slot like Anchor
+                        let limit = limit.unwrap_or(10) as usize;
+                        to_binary(&BidPoolsResponse {
+                            bid_pools: self
+                                .bid_pools
+                                .iter()
+                                .filter(|pool| {
+                                    let premium_slot =
+                                        pool_slot(pool, self.collateral_info.premium_rate_per_slot)
+                                            .unwrap();
+                                    match start_after {
+                                        Some(slot) => premium_slot > slot,
+                                        None => true,
+                                    }
+                                })
+                                .take(limit)
+                                .cloned()
+                                .collect(),
+                        })
+                    }
+                    ExternalQueryMsg::CollateralInfo { .. } => to_binary(&self.collateral_info),
+                    ExternalQueryMsg::SimulateSwapOperations { offer_amount, .. } => {
+                        to_binary(&SimulateSwapOperationsResponse {
+                            amount: Uint128::try_from(
+                                Uint256::from(offer_amount) * self.luna_price,
+                            )
+                            .unwrap(),
+                        })
+                    }
+                };
+                SystemResult::Ok(ContractResult::from(res))
+            }
+            _ => self.base.handle_query(request),
+        }
+    }
+}
@@ -1,5 +1,10 @@
-use crate::state::Permission;
+use crate::state::{
+    BidTarget, DepositMode, Permission, PriceAggregation, PriceSource, WithdrawReceipt,
+};
+use crate::valuation::VaultValuation;
 use cosmwasm_std::{Addr, Binary, Decimal, Decimal256, Timestamp, Uint128, Uint256};
+pub use cw20::{AllAccountsResponse, AllowanceResponse, TokenInfoResponse};
+use cw20::{Cw20ReceiveMsg, Expiration};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -13,23 +18,123 @@ pub struct InstantiateMsg {
     pub astroport_router: Option<Addr>,
     pub lock_period: Option<u64>,
     pub withdraw_lock: Option<u64>,
+    pub max_price_age: Option<u64>,
+    pub min_price: Option<Decimal256>,
+    pub max_price: Option<Decimal256>,
+    pub price_sources: Option<Vec<PriceSource>>,
+    pub price_aggregation: Option<PriceAggregation>,
+    pub performance_fee: Option<u64>,
+    pub fee_recipient: Option<Addr>,
+    pub management_fee: Option<u64>,
+    pub deposit_fee: Option<u64>,
+    pub withdraw_fee: Option<u64>,
+    pub max_total_cap: Option<Uint128>,
+    pub max_user_deposit: Option<Uint128>,
+    pub deposit_mode: Option<DepositMode>,
+    pub epoch_mode: Option<bool>,
+    pub epoch_length: Option<u64>,
+    pub bid_reserve: Option<Uint128>,
+    pub bid_targets: Option<Vec<BidTarget>>,
+    pub strategy: Option<Addr>,
+    pub strategy_slots: Option<Vec<u8>>,
+    pub max_slot_amount: Option<Uint128>,
+    pub min_rebalance_delta: Option<Uint128>,
 }
 
+// UpdateConfig carries every config field
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
-    Deposit {},
+    Deposit {
+        min_shares: Option<Uint128>,
+        // Address receiving shares, sender by default
+        recipient: Option<String>,
+    },
+    // Deposit one native coin other than UST, swapped to UST by Astroport router
+    ZapDeposit {
+        // Min UST from swap
+        minimum_receive: Uint128,
+        min_shares: Option<Uint128>,
+        // Address receiving shares, sender by default
+        recipient: Option<String>,
+    },
+    // bLuna deposit through CW20 Send of collateral token
+    Receive(Cw20ReceiveMsg),
     WithdrawUst {
-        share: Uint128,
+        // Full balance if not given
+        share: Option<Uint128>,
+        min_amount: Option<Uint128>,
+        // Address receiving UST, sender by default
+        recipient: Option<String>,
     },
     WithdrawBLuna {
-        share: Uint128,
+        // Full balance if not given
+        share: Option<Uint128>,
+        min_amount: Option<Uint128>,
+        // Address receiving bLuna, sender by default
+        recipient: Option<String>,
+    },
+    // Withdraw slice of UST and unlocked bLuna in vault
+    WithdrawProRata {
+        // Full balance if not given
+        share: Option<Uint128>,
+        // Address receiving UST and bLuna, sender by default
+        recipient: Option<String>,
+    },
+    // Withdraw slice of freed assets for shares escrowed in withdraw receipt
+    ClaimReceipt {},
+    // Escrow shares in withdraw queue to be paid in UST later
+    RequestWithdraw {
+        // Full balance if not given
+        share: Option<Uint128>,
+    },
+    // Pay queued tickets in order from idle UST
+    FulfillWithdraw {
+        limit: Option<u32>,
+    },
+    ClaimWithdraw {
+        ticket_id: u32,
+    },
+    CancelWithdraw {
+        ticket_id: u32,
+    },
+    // Deposit UST into current epoch in epoch mode
+    RequestDeposit {},
+    // Escrow shares for redemption in current epoch in epoch mode
+    RequestRedeem {
+        // Full balance if not given
+        share: Option<Uint128>,
+    },
+    // Settle current epoch at one NAV snapshot and start next one
+    CloseEpoch {},
+    // Receive shares and UST of closed epoch
+    ClaimEpoch {
+        epoch_id: u32,
     },
     ActivateBid {},
     SubmitBid {
         amount: Uint128,
         premium_slot: u8,
     },
+    // Submit one bid per premium slot
+    SubmitBids {
+        bids: Vec<BidEntry>,
+    },
+    // Retract bid of vault into idle UST
+    RetractBid {
+        bid_idx: Uint128,
+        // Full bid if not given
+        amount: Option<Uint256>,
+    },
+    // Move UST from bids of one premium slot to another
+    RebalanceBids {
+        from_slot: u8,
+        to_slot: u8,
+        amount: Uint128,
+    },
+    // Retract and submit bids towards bid targets or strategy targets, anyone can execute
+    Rebalance {},
     ClaimLiquidation {},
     Unlock {},
     Swap {},
@@ -43,6 +148,112 @@ pub enum ExecuteMsg {
         swap_wallet: Option<Addr>,
         lock_period: Option<u64>,
         withdraw_lock: Option<u64>,
+        max_price_age: Option<u64>,
+        min_price: Option<Decimal256>,
+        max_price: Option<Decimal256>,
+        price_sources: Option<Vec<PriceSource>>,
+        price_aggregation: Option<PriceAggregation>,
+        performance_fee: Option<u64>,
+        fee_recipient: Option<Addr>,
+        management_fee: Option<u64>,
+        deposit_fee: Option<u64>,
+        withdraw_fee: Option<u64>,
+        max_total_cap: Option<Uint128>,
+        max_user_deposit: Option<Uint128>,
+        deposit_mode: Option<DepositMode>,
+        epoch_mode: Option<bool>,
+        epoch_length: Option<u64>,
+        bid_reserve: Option<Uint128>,
+        bid_targets: Option<Vec<BidTarget>>,
+        strategy: Option<String>,
+        // Removes strategy, cannot be set with strategy
+        clear_strategy: Option<bool>,
+        strategy_slots: Option<Vec<u8>>,
+        max_slot_amount: Option<Uint128>,
+        min_rebalance_delta: Option<Uint128>,
+    },
+    // Add and remove addresses of allowlist
+    UpdateAllowlist {
+        add: Vec<String>,
+        remove: Vec<String>,
+    },
+    // Move insurance reserve into vault assets
+    CoverLoss {},
+    // Accrue performance fee at current share price
+    HarvestFees {},
+    // Book UST and bLuna sent to vault directly into vault assets
+    Sync {},
+    // Send UST and bLuna sent to vault directly to recipient
+    Skim {
+        recipient: String,
+    },
+    // CW20 compatible share token messages
+    Transfer {
+        recipient: String,
+        amount: Uint128,
+    },
+    Send {
+        contract: String,
+        amount: Uint128,
+        msg: Binary,
+    },
+    IncreaseAllowance {
+        spender: String,
+        amount: Uint128,
+        expires: Option<Expiration>,
+    },
+    DecreaseAllowance {
+        spender: String,
+        amount: Uint128,
+        expires: Option<Expiration>,
+    },
+    TransferFrom {
+        owner: String,
+        recipient: String,
+        amount: Uint128,
+    },
+    BurnFrom {
+        owner: String,
+        amount: Uint128,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidEntry {
+    pub premium_slot: u8,
+    pub amount: Uint128,
+}
+
+// Query of strategy contract deciding targets of Rebalance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyQuery {
+    NextBids { vault_state: VaultState },
+}
+
+// Bids of vault given to strategy contract
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct VaultState {
+    // Idle UST over bid reserve
+    pub biddable_ust: Uint128,
+    // UST in bids per premium slot
+    pub bids: Vec<BidEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct NextBidsResponse {
+    // Target UST per premium slot, bids of other premium slots are retracted
+    pub bids: Vec<BidEntry>,
+}
+
+// Hook messages of CW20 Receive
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Cw20HookMsg {
+    DepositBLuna {
+        min_shares: Option<Uint128>,
+        // Address receiving shares, CW20 sender by default
+        recipient: Option<String>,
     },
 }
 
@@ -85,6 +296,7 @@ pub enum ExternalMsg {
     },
 }
 
+#[rustfmt::skip]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
@@ -93,11 +305,70 @@ pub enum QueryMsg {
     Config {},
     Balance { address: String },
     TotalCap {},
+    NavBreakdown {},
+    OracleStatus {},
+    Fees {},
+    // UST and bLuna held by vault but not booked
+    Surplus {},
+    // Premium slots up to max slot of collateral ranked for a bid of amount
+    SuggestSlots {
+        amount: Uint128,
+    },
+    RemainingCapacity {
+        address: String,
+    },
+    Allowlist {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    WithdrawReceipt {
+        address: String,
+    },
+    WithdrawQueue {},
+    WithdrawTickets {
+        address: String,
+        start_after: Option<u32>,
+        limit: Option<u32>,
+    },
+    // Current epoch if epoch_id is not given
+    Epoch {
+        epoch_id: Option<u32>,
+    },
+    EpochRequest {
+        address: String,
+        epoch_id: u32,
+    },
     Activatable {},
     Claimable {},
     Permission { address: String },
     Unlockable {},
     LastDepositTimestamp { address: String },
+    // CW20 compatible share token queries
+    TokenInfo {},
+    Allowance {
+        owner: String,
+        spender: String,
+    },
+    AllAccounts {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Preview of deposit and withdraw results at current state
+    PreviewDeposit {
+        amount: Uint128,
+    },
+    PreviewWithdrawUst {
+        share: Uint128,
+    },
+    PreviewWithdrawBLuna {
+        share: Uint128,
+    },
+    ConvertToShares {
+        assets: Uint128,
+    },
+    ConvertToAssets {
+        shares: Uint128,
+    },
 }
 
 // We define a custom struct for each query response
@@ -105,6 +376,9 @@ pub enum QueryMsg {
 pub struct InfoResponse {
     pub total_supply: Uint128,
     pub locked_b_luna: Uint128,
+    // Insurance reserve not counted in total cap
+    pub insurance_reserve: Uint128,
+    pub insurance_reserve_b_luna: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -118,6 +392,27 @@ pub struct ConfigResponse {
     pub astroport_router: String,
     pub lock_period: u64,
     pub withdraw_lock: u64,
+    pub max_price_age: u64,
+    pub min_price: Decimal256,
+    pub max_price: Decimal256,
+    pub price_sources: Vec<PriceSource>,
+    pub price_aggregation: PriceAggregation,
+    pub performance_fee: u64,
+    pub fee_recipient: String,
+    pub management_fee: u64,
+    pub deposit_fee: u64,
+    pub withdraw_fee: u64,
+    pub max_total_cap: Uint128,
+    pub max_user_deposit: Uint128,
+    pub deposit_mode: DepositMode,
+    pub epoch_mode: bool,
+    pub epoch_length: u64,
+    pub bid_reserve: Uint128,
+    pub bid_targets: Vec<BidTarget>,
+    pub strategy: Option<String>,
+    pub strategy_slots: Vec<u8>,
+    pub max_slot_amount: Uint128,
+    pub min_rebalance_delta: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -130,6 +425,135 @@ pub struct TotalCapResponse {
     pub total_cap: Uint128,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct NavBreakdownResponse {
+    pub valuation: VaultValuation,
+    pub total_cap: Uint128,
+    pub total_supply: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OracleStatusResponse {
+    pub price: Decimal256,
+    pub last_updated: u64,
+    // Seconds since last update
+    pub age: u64,
+    pub max_price_age: u64,
+    pub stale: bool,
+    pub in_bounds: bool,
+    // Sources used for the price
+    pub sources: Vec<PriceSource>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SuggestSlotsResponse {
+    // Best premium slot first
+    pub slots: Vec<SlotSuggestion>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SlotSuggestion {
+    pub premium_slot: u8,
+    pub premium_rate: Decimal256,
+    // UST in lower premium slots, liquidated before this slot
+    pub queued_ust: Uint256,
+    // UST in this premium slot, liquidated pro-rata with the bid
+    pub slot_ust: Uint256,
+    // premium_rate * amount / (amount + queued_ust + slot_ust)
+    pub score: Decimal256,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SurplusResponse {
+    pub ust: Uint128,
+    pub b_luna: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeesResponse {
+    pub performance_fee: u64,
+    pub fee_recipient: String,
+    pub high_water_mark: Decimal256,
+    pub share_price: Decimal256,
+    // Fee shares minted so far
+    pub accrued_fees: Uint128,
+    // Fee shares to mint at current share price
+    pub pending_fees: Uint128,
+    pub management_fee: u64,
+    pub last_fee_accrual: u64,
+    // Management fee shares minted so far
+    pub accrued_management_fees: Uint128,
+    // Management fee shares to mint since last accrual
+    pub pending_management_fees: Uint128,
+}
+
+// UST that can be added to total cap, after deposit fee
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RemainingCapacityResponse {
+    // Remaining until max total cap
+    pub total: Uint128,
+    // Remaining until max user deposit
+    pub user: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AllowlistResponse {
+    pub addresses: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawReceiptResponse {
+    pub receipt: Option<WithdrawReceipt>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawQueueResponse {
+    // Tickets not fulfilled yet
+    pub pending: u32,
+    pub queued_share: Uint128,
+    // UST of fulfilled tickets not claimed yet
+    pub claimable_ust: Uint128,
+    // Tickets before head are fulfilled or cancelled
+    pub head: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawTicketResponse {
+    pub id: u32,
+    pub share: Uint128,
+    pub amount: Uint128,
+    pub fulfilled: bool,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawTicketsResponse {
+    pub tickets: Vec<WithdrawTicketResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct EpochResponse {
+    pub id: u32,
+    pub start: u64,
+    pub deposit_ust: Uint128,
+    pub redeem_share: Uint128,
+    pub minted_share: Uint128,
+    pub redeemed_ust: Uint128,
+    pub rolled_share: Uint128,
+    pub closed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct EpochRequestResponse {
+    pub deposit_ust: Uint128,
+    pub redeem_share: Uint128,
+    // Shares and UST to claim once epoch is closed
+    pub claimable_share: Uint128,
+    pub claimable_ust: Uint128,
+    // Redeem shares moved into request of next epoch on claim
+    pub rolled_share: Uint128,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ActivatableResponse {
     pub activatable: bool,
@@ -150,6 +574,41 @@ pub struct UnlockableResponse {
     pub unlockable: bool,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SharesResponse {
+    pub shares: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetsResponse {
+    pub assets: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PreviewWithdrawUstResponse {
+    // UST value of share
+    pub amount: Uint128,
+    // Withdraw fee in UST value
+    pub fee: Uint128,
+    // UST sent to user from vault and retracted bids
+    pub ust_amount: Uint128,
+    pub retract_bids: bool,
+    pub retract_amount: Uint128,
+    // bLuna sent to swap wallet to cover the rest
+    pub swap_wallet: bool,
+    pub swap_b_luna: Uint128,
+    pub unlocked_b_luna: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PreviewWithdrawBLunaResponse {
+    pub amount: Uint128,
+    // Withdraw fee in bLuna
+    pub fee: Uint128,
+    // false if unlocked bLuna in vault is not enough
+    pub available: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExternalQueryMsg {
@@ -167,6 +626,21 @@ pub enum ExternalQueryMsg {
         base: String,
         quote: String,
     },
+    Simulation {
+        offer_asset: Asset,
+    },
+    SimulateSwapOperations {
+        offer_amount: Uint128,
+        operations: Vec<SwapOperation>,
+    },
+    BidPoolsByCollateral {
+        collateral_token: String,
+        start_after: Option<u8>,
+        limit: Option<u8>,
+    },
+    CollateralInfo {
+        collateral_token: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -194,6 +668,29 @@ pub struct BidResponse {
     pub scale_snapshot: Uint128,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidPoolsResponse {
+    pub bid_pools: Vec<BidPoolResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidPoolResponse {
+    pub sum_snapshot: Decimal256,
+    pub product_snapshot: Decimal256,
+    pub total_bid_amount: Uint256,
+    pub premium_rate: Decimal256,
+    pub current_epoch: Uint128,
+    pub current_scale: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CollateralInfoResponse {
+    pub collateral_token: String,
+    pub bid_threshold: Uint256,
+    pub max_slot: u8,
+    pub premium_rate_per_slot: Decimal256,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct PriceResponse {
     pub rate: Decimal256,
@@ -201,6 +698,18 @@ pub struct PriceResponse {
     pub last_updated_quote: u64,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulationResponse {
+    pub return_amount: Uint128,
+    pub spread_amount: Uint128,
+    pub commission_amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulateSwapOperationsResponse {
+    pub amount: Uint128,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct TimestampResponse {
     pub timestamp: Timestamp,
@@ -225,3 +734,9 @@ pub enum AssetInfo {
     Token { contract_addr: Addr },
     NativeToken { denom: String },
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Asset {
+    pub info: AssetInfo,
+    pub amount: Uint128,
+}
@@ -0,0 +1,174 @@
+use cosmwasm_std::{Decimal256, Deps, Env, StdResult, Uint128, Uint256};
+
+use crate::error::ContractError;
+use crate::msg::AssetInfo::Token;
+use crate::msg::SwapOperation::NativeSwap;
+use crate::msg::{
+    Asset, ExternalQueryMsg, PriceResponse, SimulateSwapOperationsResponse, SimulationResponse,
+};
+use crate::state::{PriceAggregation, PriceSource, State};
+use crate::ContractError::{PriceOutOfBounds, StalePrice};
+
+// bLuna amount to simulate pool price with
+const SIMULATION_AMOUNT: u128 = 1_000_000;
+
+// bLuna price in UST and its last update time
+pub struct Price {
+    pub rate: Decimal256,
+    pub last_updated: u64,
+    // Sources used for the price
+    pub sources: Vec<PriceSource>,
+}
+
+// price_oracle first, then secondary sources
+pub fn price_sources(state: &State) -> Vec<PriceSource> {
+    let mut sources = vec![PriceSource::Oracle {
+        contract: state.price_oracle.clone(),
+    }];
+    sources.extend(state.price_sources.iter().cloned());
+    sources
+}
+
+fn query_source_price(
+    deps: Deps,
+    env: &Env,
+    state: &State,
+    source: &PriceSource,
+) -> StdResult<Price> {
+    match source {
+        PriceSource::Oracle { contract } => {
+            let res: PriceResponse = deps.querier.query_wasm_smart(
+                contract.to_string(),
+                &ExternalQueryMsg::Price {
+                    base: state.collateral_token.to_string(),
+                    quote: "uusd".to_string(),
+                },
+            )?;
+            Ok(Price {
+                rate: res.rate,
+                // Oracle returns u64::MAX for uusd
+                last_updated: res.last_updated_base.min(res.last_updated_quote),
+                sources: vec![source.clone()],
+            })
+        }
+        PriceSource::AstroportPool { pair } => {
+            // bLuna -> LUNA on the pair
+            let res: SimulationResponse = deps.querier.query_wasm_smart(
+                pair.to_string(),
+                &ExternalQueryMsg::Simulation {
+                    offer_asset: Asset {
+                        info: Token {
+                            contract_addr: state.collateral_token.clone(),
+                        },
+                        amount: Uint128::from(SIMULATION_AMOUNT),
+                    },
+                },
+            )?;
+            // Spot amount without spread and commission
+            let luna_amount = res.return_amount + res.spread_amount + res.commission_amount;
+            // LUNA -> UST with native swap rate
+            let res: SimulateSwapOperationsResponse = deps.querier.query_wasm_smart(
+                state.astroport_router.to_string(),
+                &ExternalQueryMsg::SimulateSwapOperations {
+                    offer_amount: luna_amount,
+                    operations: vec![NativeSwap {
+                        offer_denom: "uluna".to_string(),
+                        ask_denom: "uusd".to_string(),
+                    }],
+                },
+            )?;
+            // Pool price is always up to date
+            Ok(Price {
+                rate: Decimal256::from_ratio(Uint256::from(res.amount), SIMULATION_AMOUNT),
+                last_updated: env.block.time.seconds(),
+                sources: vec![source.clone()],
+            })
+        }
+    }
+}
+
+// Fetch bLuna price from configured sources
+// Falls back to price_oracle result if no source is valid, so that check_price rejects it
+// or its query error is returned
+// Pool price can be moved by a swap in the same transaction, so it is never used alone
+pub fn query_price(deps: Deps, env: &Env, state: &State) -> StdResult<Price> {
+    let mut sources = price_sources(state).into_iter();
+    // Primary source failure is handled like an invalid price
+    let primary = query_source_price(deps, env, state, &sources.next().unwrap());
+    let primary_valid = match &primary {
+        Ok(price) => is_valid(env, state, price),
+        Err(_) => false,
+    };
+    if primary_valid && state.price_aggregation == PriceAggregation::Fallback {
+        return primary;
+    }
+    let mut prices = vec![];
+    for source in sources {
+        // Secondary source failure is skipped
+        if let Ok(price) = query_source_price(deps, env, state, &source) {
+            if is_valid(env, state, &price) {
+                if state.price_aggregation == PriceAggregation::Fallback {
+                    if is_pool(&source) {
+                        continue;
+                    }
+                    return Ok(price);
+                }
+                prices.push(price);
+            }
+        }
+    }
+    if primary_valid {
+        prices.push(primary?);
+    } else if prices.is_empty() || (prices.len() == 1 && is_pool(&prices[0].sources[0])) {
+        return primary;
+    }
+    prices.sort_by_key(|price| price.rate);
+    let mid = prices.len() / 2;
+    let rate = if prices.len() % 2 == 0 {
+        (prices[mid - 1].rate + prices[mid].rate) / Uint256::from(2u8)
+    } else {
+        prices[mid].rate
+    };
+    Ok(Price {
+        rate,
+        last_updated: prices.iter().map(|p| p.last_updated).min().unwrap(),
+        sources: prices.into_iter().flat_map(|p| p.sources).collect(),
+    })
+}
+
+fn is_pool(source: &PriceSource) -> bool {
+    matches!(source, PriceSource::AstroportPool { .. })
+}
+
+fn is_valid(env: &Env, state: &State, price: &Price) -> bool {
+    price_age(env, price.last_updated) <= state.max_price_age && price_in_bounds(state, price.rate)
+}
+
+// Seconds since last oracle update
+pub fn price_age(env: &Env, last_updated: u64) -> u64 {
+    env.block.time.seconds().saturating_sub(last_updated)
+}
+
+pub fn price_in_bounds(state: &State, rate: Decimal256) -> bool {
+    !rate.is_zero() && rate >= state.min_price && rate <= state.max_price
+}
+
+// Reject stale or out of bounds price for share calculation
+pub fn check_price(
+    env: &Env,
+    state: &State,
+    rate: Decimal256,
+    last_updated: u64,
+) -> Result<(), ContractError> {
+    let age = price_age(env, last_updated);
+    if age > state.max_price_age {
+        return Err(StalePrice {
+            age,
+            max_age: state.max_price_age,
+        });
+    }
+    if !price_in_bounds(state, rate) {
+        return Err(PriceOutOfBounds { price: rate });
+    }
+    Ok(())
+}
@@ -1,13 +1,18 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Addr, Timestamp, Uint128};
+use cosmwasm_std::{Addr, Decimal256, Timestamp, Uint128};
+use cw20::AllowanceResponse;
 use cw_storage_plus::{Item, Map, U32Key};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     pub owner: Addr,
     pub total_supply: Uint128,
+    // UST in vault booked by deposits, withdrawals, bids and swaps
+    pub idle_ust: Uint128,
+    // Unlocked bLuna in vault booked by unlocks, withdrawals and swaps
+    pub free_b_luna: Uint128,
     pub locked_b_luna: Uint128,
     pub swap_wallet: Addr,
     pub paused: bool,
@@ -17,6 +22,91 @@ pub struct State {
     pub astroport_router: Addr,
     pub lock_period: u64,
     pub withdraw_lock: u64,
+    pub max_price_age: u64,
+    pub min_price: Decimal256,
+    pub max_price: Decimal256,
+    pub price_sources: Vec<PriceSource>,
+    pub price_aggregation: PriceAggregation,
+    // Performance fee in bps of share price gain over high-water mark
+    pub performance_fee: u64,
+    pub fee_recipient: Addr,
+    // Highest share price fees were charged at
+    pub high_water_mark: Decimal256,
+    // Fee shares minted to fee recipient so far
+    pub accrued_fees: Uint128,
+    // Annual management fee in bps of total supply
+    pub management_fee: u64,
+    // Last time management fee was charged
+    pub last_fee_accrual: u64,
+    // Management fee shares minted to fee recipient so far
+    pub accrued_management_fees: Uint128,
+    // Deposit and withdraw fee in bps, paid into insurance reserve
+    pub deposit_fee: u64,
+    pub withdraw_fee: u64,
+    // UST and bLuna in vault kept out of total cap to cover losses
+    pub insurance_reserve: Uint128,
+    pub insurance_reserve_b_luna: Uint128,
+    // Max total cap after deposit and max UST value of shares per user
+    pub max_total_cap: Uint128,
+    pub max_user_deposit: Uint128,
+    pub deposit_mode: DepositMode,
+    // Batch deposits and redemptions per epoch, settled at one NAV snapshot
+    pub epoch_mode: bool,
+    // Seconds from epoch start until it can be closed
+    pub epoch_length: u64,
+    pub current_epoch: u32,
+    pub epoch_start: u64,
+    // UST of open epoch deposits and unclaimed epoch redemptions, not counted in idle UST
+    pub epoch_ust: Uint128,
+    // UST kept idle for withdrawals, not submitted to bids
+    pub bid_reserve: Uint128,
+    // Target allocation of bids per premium slot, kept by Rebalance
+    pub bid_targets: Vec<BidTarget>,
+    // Contract deciding targets of Rebalance instead of bid targets
+    pub strategy: Option<Addr>,
+    // Guardrails of strategy: premium slots it can bid at and max target per slot
+    pub strategy_slots: Vec<u8>,
+    pub max_slot_amount: Uint128,
+    // Min difference between target and bids of a premium slot moved by Rebalance
+    pub min_rebalance_delta: Uint128,
+}
+
+// Target bid amount of a premium slot
+// Weight in bps of UST in bids and idle UST over bid reserve, clamped to min and max amount
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidTarget {
+    pub premium_slot: u8,
+    pub weight: u64,
+    pub min_amount: Uint128,
+    pub max_amount: Uint128,
+}
+
+// Secondary bLuna price source besides price_oracle
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+    // Anchor compatible price oracle
+    Oracle { contract: Addr },
+    // Astroport bLuna/LUNA pair chained with native LUNA/UST swap rate
+    AstroportPool { pair: Addr },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceAggregation {
+    // First valid price in order of price_oracle and price_sources
+    Fallback,
+    // Median of all valid prices
+    Median,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositMode {
+    // Anyone can deposit and receive shares
+    Open,
+    // Only addresses in allowlist can deposit and receive shares
+    Allowlist,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -25,6 +115,81 @@ pub struct TokenRecord {
     pub timestamp: Timestamp,
 }
 
+// Shares escrowed on pro-rata withdrawals of a user for assets kept in vault
+// Slices are of escrowed shares at last settlement, paid out by ClaimReceipt as they free up
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawReceipt {
+    pub share: Uint128,
+    pub bid_ust: Uint128,
+    pub pending_collateral: Uint128,
+    pub locked_b_luna: Uint128,
+    pub timestamp: Timestamp,
+}
+
+// Shares escrowed to be paid in UST once vault has enough idle UST
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawTicket {
+    pub owner: Addr,
+    pub share: Uint128,
+    // UST to claim after withdraw fee, set on fulfilment
+    pub amount: Uint128,
+    pub fulfilled: bool,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct WithdrawQueue {
+    pub next_id: u32,
+    // Tickets before head are fulfilled or cancelled
+    pub head: u32,
+    // Tickets not fulfilled yet and their shares
+    pub pending: u32,
+    pub queued_share: Uint128,
+    // UST of fulfilled tickets not claimed yet, not counted in idle UST
+    pub claimable_ust: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct Epoch {
+    pub start: u64,
+    // UST deposited and shares escrowed for redemption in epoch
+    pub deposit_ust: Uint128,
+    pub redeem_share: Uint128,
+    // Shares minted for deposits and UST for redemptions after fees, set on close
+    pub minted_share: Uint128,
+    pub redeemed_ust: Uint128,
+    // Redeem shares idle UST could not cover, rolled into next epoch on close
+    pub rolled_share: Uint128,
+    pub closed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct EpochRequest {
+    pub deposit_ust: Uint128,
+    pub redeem_share: Uint128,
+}
+
+// Zap deposit waiting for swap result
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ZapDeposit {
+    pub sender: Addr,
+    pub recipient: Option<String>,
+    pub min_shares: Option<Uint128>,
+    // UST balance of vault before swap
+    pub ust_snapshot: Uint128,
+}
+
+// Bid retraction waiting for retracted UST
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidRetraction {
+    // UST balance of vault before retraction
+    pub ust_snapshot: Uint128,
+    // Least UST to receive from retraction
+    pub amount: Uint128,
+    // (premium slot, amount) bids to submit after retraction
+    pub resubmit: Vec<(u8, Uint128)>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Permission {
     pub submit_bid: bool,
@@ -32,10 +197,43 @@ pub struct Permission {
 
 pub const BALANCES: Map<&[u8], Uint128> = Map::new("balance");
 
+// (owner, spender) -> allowance of vault shares
+pub const ALLOWANCES: Map<(&[u8], &[u8]), AllowanceResponse> = Map::new("allowance");
+
 pub const LAST_DEPOSIT: Map<&[u8], Timestamp> = Map::new("last_deposit");
 
+// Shares credited to existing holder by third party deposits and time of the last one
+// Only these shares are locked for withdraw lock, other shares of the holder stay free
+pub const LOCKED_SHARES: Map<&[u8], TokenRecord> = Map::new("locked_share");
+
 pub const PERMISSIONS: Map<&[u8], Permission> = Map::new("permission");
 
+pub const WITHDRAW_RECEIPTS: Map<&[u8], WithdrawReceipt> = Map::new("withdraw_receipt");
+
+pub const WITHDRAW_QUEUE: Item<WithdrawQueue> = Item::new("withdraw_queue");
+
+pub const WITHDRAW_TICKETS: Map<U32Key, WithdrawTicket> = Map::new("withdraw_ticket");
+
+// (owner, ticket id) index of withdraw tickets
+pub const USER_TICKETS: Map<(&[u8], U32Key), bool> = Map::new("user_ticket");
+
+pub const EPOCHS: Map<U32Key, Epoch> = Map::new("epoch");
+
+// (user, epoch id) -> deposit and redemption of user in epoch
+pub const EPOCH_REQUESTS: Map<(&[u8], U32Key), EpochRequest> = Map::new("epoch_request");
+
+// Addresses allowed to deposit and receive shares in allowlist mode
+pub const ALLOWLIST: Map<&[u8], bool> = Map::new("allowlist");
+
 pub const STATE: Item<State> = Item::new("state");
 
+// UST balance of vault before swap, to book swap result in reply
+pub const UST_SNAPSHOT: Item<Uint128> = Item::new("ust_snapshot");
+
+// Shares of zap deposit are minted in reply
+pub const ZAP_DEPOSIT: Item<ZapDeposit> = Item::new("zap_deposit");
+
+// Retracted UST is booked and resubmitted in reply
+pub const BID_RETRACTION: Item<BidRetraction> = Item::new("bid_retraction");
+
 pub const CLAIM_LIST: Map<U32Key, TokenRecord> = Map::new("claim_list");
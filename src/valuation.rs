@@ -0,0 +1,208 @@
+use cosmwasm_std::{Decimal256, Deps, Env, Fraction, StdError, StdResult, Uint128, Uint256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::ops::Mul;
+
+use crate::error::ContractError;
+use crate::msg::{
+    BidPoolResponse, BidPoolsResponse, BidResponse, BidsResponse, CollateralInfoResponse,
+    Cw20BalanceResponse, ExternalQueryMsg,
+};
+use crate::oracle::{check_price, query_price};
+use crate::state::{PriceSource, State};
+
+// Page size of BidsByUser and BidPoolsByCollateral queries on Anchor liquidation queue
+const BIDS_PAGE_LIMIT: u8 = 31;
+
+// Virtual shares and assets added to both sides of share conversion
+// so that share price can not be moved much while supply is tiny
+pub const VIRTUAL_SHARES: u128 = 1_000;
+pub const VIRTUAL_ASSETS: u128 = 1_000;
+// Shares minted to the vault itself on first deposit, never burned
+pub const DEAD_SHARES: u128 = 1_000;
+
+// Assets of the vault in vault and in Anchor
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct VaultValuation {
+    // UST in vault
+    pub idle_ust: Uint128,
+    // UST waiting for liquidation in Anchor bids
+    pub bid_ust: Uint128,
+    // Liquidated bLuna not claimed from Anchor yet
+    pub pending_collateral: Uint128,
+    // Claimed bLuna in lock period
+    pub locked_b_luna: Uint128,
+    // Unlocked bLuna in vault
+    pub free_b_luna: Uint128,
+    // bLuna price in UST
+    pub price: Decimal256,
+    // Last update time of price
+    pub price_updated: u64,
+    // Sources used for the price
+    pub price_sources: Vec<PriceSource>,
+}
+
+impl VaultValuation {
+    pub fn total_ust(&self) -> Uint128 {
+        self.idle_ust + self.bid_ust
+    }
+
+    pub fn total_b_luna(&self) -> Uint128 {
+        self.pending_collateral + self.locked_b_luna + self.free_b_luna
+    }
+
+    // Total cap in UST
+    pub fn total_cap(&self) -> StdResult<Uint128> {
+        Ok(
+            Uint128::try_from(Uint256::from(self.total_b_luna()).mul(self.price))?
+                + self.total_ust(),
+        )
+    }
+
+    // Total cap in bLuna
+    pub fn total_cap_in_b_luna(&self) -> StdResult<Uint128> {
+        Ok(self.total_b_luna() + self.to_b_luna(self.total_ust())?)
+    }
+
+    // bLuna amount of UST at price
+    pub fn to_b_luna(&self, ust_amount: Uint128) -> StdResult<Uint128> {
+        let inv_price = self
+            .price
+            .inv()
+            .ok_or_else(|| StdError::generic_err("Zero price"))?;
+        Ok(Uint128::try_from(Uint256::from(ust_amount).mul(inv_price))?)
+    }
+
+    // Fail if price is not valid to mint or burn shares
+    pub fn check_price(&self, env: &Env, state: &State) -> Result<(), ContractError> {
+        check_price(env, state, self.price, self.price_updated)
+    }
+}
+
+// Shares for assets at total assets and total supply
+pub fn convert_to_shares(assets: Uint128, total_assets: Uint128, total_supply: Uint128) -> Uint128 {
+    assets.multiply_ratio(
+        total_supply.u128() + VIRTUAL_SHARES,
+        total_assets.u128() + VIRTUAL_ASSETS,
+    )
+}
+
+// Assets for shares at total assets and total supply
+pub fn convert_to_assets(shares: Uint128, total_assets: Uint128, total_supply: Uint128) -> Uint128 {
+    shares.multiply_ratio(
+        total_assets.u128() + VIRTUAL_ASSETS,
+        total_supply.u128() + VIRTUAL_SHARES,
+    )
+}
+
+// Iterate all bids of the vault in Anchor liquidation queue
+pub fn query_bids(deps: Deps, env: &Env, state: &State) -> StdResult<Vec<BidResponse>> {
+    let mut bids = vec![];
+    let mut start_after: Option<Uint128> = Some(Uint128::zero());
+    loop {
+        let res: BidsResponse = deps.querier.query_wasm_smart(
+            state.anchor_liquidation_queue.to_string(),
+            &ExternalQueryMsg::BidsByUser {
+                collateral_token: state.collateral_token.to_string(),
+                bidder: env.contract.address.to_string(),
+                start_after,
+                limit: Some(BIDS_PAGE_LIMIT),
+            },
+        )?;
+        let len = res.bids.len();
+        start_after = res.bids.last().map(|bid| bid.idx);
+        bids.extend(res.bids);
+        if len < BIDS_PAGE_LIMIT as usize {
+            break;
+        }
+    }
+    Ok(bids)
+}
+
+// (premium slot, bid pool) of collateral on Anchor liquidation queue, in premium slot order
+// Anchor creates bid pools lazily, so premium slots without bids can be missing
+pub fn query_bid_pools(
+    deps: Deps,
+    state: &State,
+    premium_rate_per_slot: Decimal256,
+) -> StdResult<Vec<(u8, BidPoolResponse)>> {
+    let mut bid_pools = vec![];
+    let mut start_after: Option<u8> = None;
+    loop {
+        let res: BidPoolsResponse = deps.querier.query_wasm_smart(
+            state.anchor_liquidation_queue.to_string(),
+            &ExternalQueryMsg::BidPoolsByCollateral {
+                collateral_token: state.collateral_token.to_string(),
+                start_after,
+                limit: Some(BIDS_PAGE_LIMIT),
+            },
+        )?;
+        let len = res.bid_pools.len();
+        for pool in res.bid_pools {
+            bid_pools.push((pool_slot(&pool, premium_rate_per_slot)?, pool));
+        }
+        if len < BIDS_PAGE_LIMIT as usize {
+            break;
+        }
+        start_after = bid_pools.last().map(|(premium_slot, _)| *premium_slot);
+    }
+    Ok(bid_pools)
+}
+
+// Premium slot of bid pool, premium rate over premium rate per slot
+pub fn pool_slot(pool: &BidPoolResponse, premium_rate_per_slot: Decimal256) -> StdResult<u8> {
+    if premium_rate_per_slot.is_zero() {
+        return Err(StdError::generic_err("Zero premium rate per slot"));
+    }
+    let per_slot = premium_rate_per_slot.numerator();
+    let premium_slot = (pool.premium_rate.numerator() + per_slot / Uint256::from(2u8)) / per_slot;
+    Uint128::try_from(premium_slot)
+        .ok()
+        .and_then(|premium_slot| u8::try_from(premium_slot.u128()).ok())
+        .ok_or_else(|| StdError::generic_err("Premium slot overflow"))
+}
+
+pub fn query_collateral_info(deps: Deps, state: &State) -> StdResult<CollateralInfoResponse> {
+    deps.querier.query_wasm_smart(
+        state.anchor_liquidation_queue.to_string(),
+        &ExternalQueryMsg::CollateralInfo {
+            collateral_token: state.collateral_token.to_string(),
+        },
+    )
+}
+
+// bLuna balance of the vault
+pub fn query_b_luna_balance(deps: Deps, env: &Env, state: &State) -> StdResult<Uint128> {
+    let res: Cw20BalanceResponse = deps.querier.query_wasm_smart(
+        state.collateral_token.to_string(),
+        &ExternalQueryMsg::Balance {
+            address: env.contract.address.to_string(),
+        },
+    )?;
+    Ok(res.balance)
+}
+
+// UST and bLuna in vault are taken from internally tracked balances,
+// tokens sent to the vault directly are not counted
+pub fn query_valuation(deps: Deps, env: &Env, state: &State) -> StdResult<VaultValuation> {
+    let mut bid_ust = Uint128::zero();
+    let mut pending_collateral = Uint128::zero();
+    for bid in query_bids(deps, env, state)? {
+        // Waiting UST for liquidation
+        bid_ust += Uint128::try_from(bid.amount)?;
+        // Pending bLuna in Anchor
+        pending_collateral += Uint128::try_from(bid.pending_liquidated_collateral)?;
+    }
+    let price = query_price(deps, env, state)?;
+    Ok(VaultValuation {
+        idle_ust: state.idle_ust,
+        bid_ust,
+        pending_collateral,
+        locked_b_luna: state.locked_b_luna,
+        free_b_luna: state.free_b_luna,
+        price: price.rate,
+        price_updated: price.last_updated,
+        price_sources: price.sources,
+    })
+}